@@ -3,7 +3,12 @@ use std::env;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
+use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::model::ErrorSeverity;
+use crate::statistics::leaderboards::TieRanking;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Config {
@@ -14,8 +19,131 @@ pub struct Config {
     #[serde(default = "HashMap::new")]
     pub kickbacks: HashMap<String, Kickback>,
     pub statistics: Option<StatisticsConfig>,
+    #[serde(default)]
+    pub offline_queue: Option<OfflineQueueConfig>,
+    #[serde(default = "HashMap::new")]
+    pub channel_settings: HashMap<String, ChannelSettings>,
+}
+
+impl Config {
+    /// Whether server start, stop and crash messages for the channel should be sent to Discord.
+    pub fn relays_lifecycle(&self, channel: &str) -> bool {
+        self.channel_settings
+            .get(channel)
+            .is_none_or(|settings| settings.relay_lifecycle)
+    }
+
+    /// The game version to show for the channel, preferring the configured display override over
+    /// the version reported by the server.
+    pub fn display_game_version<'a>(&'a self, channel: &str, game_version: &'a str) -> &'a str {
+        self.channel_settings
+            .get(channel)
+            .and_then(|settings| settings.game_version_display.as_deref())
+            .unwrap_or(game_version)
+    }
+
+    /// The commands to send to the channel's server when it starts.
+    pub fn on_start_commands(&self, channel: &str) -> &[String] {
+        self.channel_settings
+            .get(channel)
+            .map_or(&[], |settings| &settings.on_start_commands)
+    }
+
+    /// The webhook username to relay a player's chat in the channel with.
+    pub fn relay_username(&self, channel: &str, name: &str) -> String {
+        let template = self
+            .channel_settings
+            .get(channel)
+            .and_then(|settings| settings.relay_username_template.as_deref());
+        let username = match template {
+            Some(template) => template
+                .replace("{channel}", channel)
+                .replace("{name}", name),
+            None => name.to_owned(),
+        };
+        // Discord rejects webhook usernames longer than this
+        username.chars().take(MAX_WEBHOOK_USERNAME_LENGTH).collect()
+    }
+
+    /// Compiles the chat filters of every channel that has any.
+    pub fn chat_filters(&self) -> Result<HashMap<String, RegexSet>, ConfigError> {
+        let mut filters = HashMap::new();
+        for (channel, settings) in &self.channel_settings {
+            if settings.chat_filters.is_empty() {
+                continue;
+            }
+            // Compiled one at a time first, since a set doesn't say which pattern was invalid
+            for pattern in &settings.chat_filters {
+                Regex::new(pattern).map_err(|source| ConfigError::InvalidChatFilter {
+                    channel: channel.clone(),
+                    pattern: pattern.clone(),
+                    source,
+                })?;
+            }
+            let filter = RegexSet::new(&settings.chat_filters)
+                .expect("chat filters were already compiled individually");
+            filters.insert(channel.clone(), filter);
+        }
+        Ok(filters)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("invalid chat filter '{pattern}' for channel '{channel}': {source}")]
+    InvalidChatFilter {
+        channel: String,
+        pattern: String,
+        source: regex::Error,
+    },
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelSettings {
+    #[serde(default = "default_relay_lifecycle")]
+    pub relay_lifecycle: bool,
+    /// Commands sent to the server whenever it starts, e.g. to set up scoreboards.
+    #[serde(default)]
+    pub on_start_commands: Vec<String>,
+    /// Shown in place of the game version reported by the server, e.g. `1.20.1 (Fabric)`.
+    #[serde(default)]
+    pub game_version_display: Option<String>,
+    /// Regex patterns; chat messages matching any of them aren't relayed to Discord.
+    #[serde(default)]
+    pub chat_filters: Vec<String>,
+    /// The template for the webhook username of relayed chat, e.g. `[Survival] {name}`. `{name}`
+    /// is replaced with the player name and `{channel}` with the channel.
+    #[serde(default)]
+    pub relay_username_template: Option<String>,
+}
+
+fn default_relay_lifecycle() -> bool {
+    true
+}
+
+/// Buffers messages for servers that are offline, delivering them when the server reconnects.
+/// Queued commands are usually worth delivering late (e.g. moderation), while chat is mostly
+/// stale by the time a server comes back, so it is only queued if `include_chat` is set.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OfflineQueueConfig {
+    #[serde(default = "default_offline_queue_max_messages")]
+    pub max_messages: usize,
+    #[serde(default = "default_offline_queue_ttl_seconds")]
+    pub ttl_seconds: u64,
+    #[serde(default)]
+    pub include_chat: bool,
+}
+
+fn default_offline_queue_max_messages() -> usize {
+    50
+}
+
+fn default_offline_queue_ttl_seconds() -> u64 {
+    10 * 60
+}
+
+const MAX_WEBHOOK_USERNAME_LENGTH: usize = 80;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DiscordConfig {
     pub token: String,
@@ -23,12 +151,34 @@ pub struct DiscordConfig {
     pub ping_interval_minutes: u16,
     #[serde(default = "default_lfp_ping_interval_minutes")]
     pub lfp_ping_interval_minutes: u16,
+    /// The reaction used to register as looking for players: a unicode emoji, a custom emoji as
+    /// `<:name:id>`, or a custom emoji id. Defaults to 👋.
+    #[serde(default)]
+    pub lfp_reaction: Option<String>,
+    /// The gateway intents to request, by name (e.g. `GUILD_MESSAGES`), overriding the default
+    /// set of everything the bot uses. Leaving out the privileged `MESSAGE_CONTENT` intent means
+    /// relayed chat and text commands will arrive without any content.
+    #[serde(default)]
+    pub intents: Option<Vec<String>>,
     #[serde(default)]
     pub relay_channel_topic: bool,
     #[serde(default)]
     pub player_avatar_url: Option<String>,
     #[serde(default)]
+    pub ping_default_avatar_url: Option<String>,
+    #[serde(default)]
     pub error_webhook: Option<ErrorWebhookConfig>,
+    /// Overrides `error_webhook` for errors of specific severities.
+    #[serde(default)]
+    pub error_webhooks: HashMap<ErrorSeverity, ErrorWebhookConfig>,
+}
+
+impl DiscordConfig {
+    pub fn error_webhook_for(&self, severity: ErrorSeverity) -> Option<&ErrorWebhookConfig> {
+        self.error_webhooks
+            .get(&severity)
+            .or(self.error_webhook.as_ref())
+    }
 }
 
 fn default_ping_interval_minutes() -> u16 {
@@ -49,11 +199,77 @@ pub struct ErrorWebhookConfig {
 pub struct WebServerConfig {
     pub port: u16,
     pub max_query_size: u32,
+    #[serde(default = "default_mojang_cache_size")]
+    pub mojang_cache_size: usize,
+    /// How long a resolved username is cached for before being fetched again.
+    #[serde(
+        default = "default_mojang_cache_ttl_hours",
+        alias = "mojang_cache_clear_interval_hours"
+    )]
+    pub mojang_cache_ttl_hours: u64,
+    #[serde(default = "default_mojang_request_timeout_seconds")]
+    pub mojang_request_timeout_seconds: u64,
+    /// The largest request body accepted by routes that take one, in bytes.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    /// Bearer token required by admin routes. Admin routes are unavailable if this is unset.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// The header that trusted proxies put the client IP in, e.g. `X-Forwarded-For` or `X-Real-IP`.
+    #[serde(default = "default_client_ip_header")]
+    pub client_ip_header: String,
+    /// CIDR ranges of reverse proxies whose client IP header is trusted. Without any, the
+    /// socket peer address is always used as the client IP.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// If set, `/stats/player/<uuid>` only returns statistics in this namespace. Statistics in
+    /// every namespace are then only returned with `?all_namespaces=true`.
+    #[serde(default)]
+    pub default_namespace: Option<String>,
+    /// Base URL that Bedrock gamertags are looked up from by XUID, e.g.
+    /// `https://api.geysermc.org/v2/xbox/gamertag`. Bedrock usernames can't be resolved if unset.
+    #[serde(default)]
+    pub bedrock_username_url: Option<String>,
+}
+
+fn default_mojang_cache_size() -> usize {
+    512
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_client_ip_header() -> String {
+    "X-Forwarded-For".to_owned()
+}
+
+fn default_mojang_cache_ttl_hours() -> u64 {
+    24
+}
+
+fn default_mojang_request_timeout_seconds() -> u64 {
+    5
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct IntegrationsConfig {
     pub port: u16,
+    /// Connections beyond this many concurrent clients are rejected.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// If set, integrations clients may also connect over WebSocket at `/integrations` on this
+    /// port, speaking the same JSON messages as text frames.
+    #[serde(default)]
+    pub websocket_port: Option<u16>,
+    /// How long a TCP connection may sit idle before keepalive probes are sent, and how long
+    /// between probes, so that dead servers are noticed without waiting for a failed write.
+    #[serde(default = "default_keepalive_interval_seconds")]
+    pub keepalive_interval_seconds: u64,
+}
+
+fn default_keepalive_interval_seconds() -> u64 {
+    30
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -77,20 +293,153 @@ pub struct StatisticsConfig {
     pub database_url: String,
     pub database_name: String,
     pub leaderboards_dir: Option<PathBuf>,
+    /// How many leaderboards may be rebuilt at once, each holding its own database connections.
+    #[serde(default = "default_leaderboard_rebuild_concurrency")]
+    pub leaderboard_rebuild_concurrency: usize,
+    /// How players with equal values are ranked on leaderboards.
+    #[serde(default)]
+    pub leaderboard_tie_ranking: TieRanking,
+    /// If set, leaderboards rebuilt within this many seconds of startup aren't rebuilt again at
+    /// startup. Otherwise, every leaderboard is rebuilt whenever the backend starts.
+    #[serde(default)]
+    pub startup_rebuild_freshness_seconds: Option<u64>,
+    /// The IANA timezone (e.g. `Europe/London`) whose calendar days are used to bucket games
+    /// by day, month and year. ClickHouse stores `date_played` as an instant, so changing this
+    /// only moves bucket boundaries and does not require migrating existing data. Games played
+    /// before this option existed were written as GMT, which is the same instant as UTC.
+    #[serde(default = "default_statistics_timezone")]
+    pub timezone: String,
+    /// If set, games older than this many days are permanently deleted along with their
+    /// statistics, once at startup and then daily. Deletes in ClickHouse are mutations which
+    /// rewrite every affected data part, so a pruning pass can be slow and I/O heavy on large
+    /// tables. Leaderboards are rebuilt afterwards.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    /// If set, statistics bundles with more rows than this (counting every player and global
+    /// statistic) are rejected rather than inserted.
+    #[serde(default)]
+    pub max_bundle_rows: Option<usize>,
+    /// How long the summary returned by `/stats/stats` is cached for, as computing it scans
+    /// every statistic.
+    #[serde(default = "default_stats_cache_seconds")]
+    pub stats_cache_seconds: u64,
+    /// If set, the usernames of top-ranked players are fetched after leaderboards are rebuilt so
+    /// that they're already cached when the leaderboards are viewed.
+    #[serde(default)]
+    pub username_warmer: Option<UsernameWarmerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsernameWarmerConfig {
+    /// Usernames are fetched for players ranked this high or better on any leaderboard.
+    #[serde(default = "default_username_warmer_top_players")]
+    pub top_players: i64,
+    /// How long to wait between fetching each username, to stay clear of Mojang's rate limits.
+    #[serde(default = "default_username_warmer_spacing_millis")]
+    pub spacing_millis: u64,
+}
+
+fn default_username_warmer_top_players() -> i64 {
+    10
+}
+
+fn default_username_warmer_spacing_millis() -> u64 {
+    500
+}
+
+fn default_statistics_timezone() -> String {
+    "UTC".to_owned()
+}
+
+fn default_leaderboard_rebuild_concurrency() -> usize {
+    4
+}
+
+fn default_stats_cache_seconds() -> u64 {
+    60
 }
 
+const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+/// Loads the config from the path given as the first command line argument, or otherwise from
+/// the `CONFIG_PATH` environment variable, or otherwise from `config.json`.
+///
+/// A missing config is only created with default values when started with `--init`, since the
+/// defaults aren't useful to run with and the filesystem may well be read-only.
 pub(super) fn load() -> Config {
-    let config_path = env::var("CONFIG_PATH").unwrap_or_else(|_| "config.json".to_owned());
-    let path = Path::new(&config_path);
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let init = flags.iter().any(|flag| flag == "--init");
+
+    let explicit_path = args
+        .into_iter()
+        .next()
+        .or_else(|| env::var("CONFIG_PATH").ok());
+    let config_path = explicit_path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+    let path = Path::new(config_path);
     if path.exists() {
         let mut file = File::open(path).expect("failed to open config");
         serde_json::from_reader(&mut file).expect("failed to parse config")
-    } else {
+    } else if init {
         let config = Config::default();
 
         let mut file = File::create(path).expect("failed to create config");
         serde_json::to_writer_pretty(&mut file, &config).expect("failed to write config");
 
         config
+    } else {
+        panic!(
+            "config file '{}' does not exist: provide one, or run with --init to create a default config",
+            config_path
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_filters(channel: &str, filters: &[&str]) -> Config {
+        let settings = serde_json::json!({ "chat_filters": filters });
+        Config {
+            channel_settings: HashMap::from([(
+                channel.to_owned(),
+                serde_json::from_value(settings).unwrap(),
+            )]),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn compiles_chat_filters_by_channel() {
+        let config = config_with_filters("survival", &["(?i)discord\\.gg", "^!"]);
+        let filters = config.chat_filters().unwrap();
+        assert!(filters["survival"].is_match("join discord.GG/abc"));
+        assert!(!filters["survival"].is_match("hello"));
+    }
+
+    #[test]
+    fn names_the_channel_and_pattern_of_an_invalid_chat_filter() {
+        let config = config_with_filters("survival", &["fine", "(unclosed"]);
+        let error = config.chat_filters().unwrap_err().to_string();
+        assert!(error.contains("'(unclosed'"), "{}", error);
+        assert!(error.contains("'survival'"), "{}", error);
+    }
+
+    #[test]
+    fn fills_in_the_relay_username_template() {
+        let settings = serde_json::json!({ "relay_username_template": "[{channel}] {name}" });
+        let config = Config {
+            channel_settings: HashMap::from([(
+                "survival".to_owned(),
+                serde_json::from_value(settings).unwrap(),
+            )]),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.relay_username("survival", "Steve"),
+            "[survival] Steve"
+        );
+        assert_eq!(config.relay_username("creative", "Steve"), "Steve");
     }
 }
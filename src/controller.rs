@@ -1,14 +1,23 @@
-use std::collections::HashMap;
-use std::time::SystemTime;
-
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::Utc;
+use regex::RegexSet;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{debug, info, warn};
 use xtra::prelude::*;
 
 use crate::database::{self, DatabaseClient};
 use crate::discord::{self, DiscordClient, ReportError};
 use crate::integrations::{self, IntegrationsClient};
 use crate::model::*;
-use crate::statistics::database::{StatisticDatabaseController, UploadStatsBundle};
-use crate::Config;
+use crate::mojang_api::MojangApiClient;
+use crate::statistics::database::{
+    StatisticDatabaseController, StatisticsDatabaseError, UploadStatsBundle,
+};
+use crate::statistics::model::GameStatsBundle;
+use crate::{Config, ConfigError};
 
 // TODO: use numerical channel ids internally?
 #[derive(Actor)]
@@ -17,19 +26,181 @@ pub struct Controller {
     discord: Option<Address<DiscordClient>>,
     database: Option<Address<DatabaseClient>>,
     statistics: Option<Address<StatisticDatabaseController>>,
+    mojang_client: Option<Address<MojangApiClient>>,
     integration_clients: HashMap<String, Address<IntegrationsClient>>,
     status_by_channel: HashMap<String, ServerStatus>,
+    /// Every channel that has registered since startup. Unlike `status_by_channel`, this is not
+    /// cleared when a server stops, so commands sent after a clean stop can still be queued.
+    known_channels: HashSet<String>,
+    client_info: HashMap<String, ClientInfo>,
+    /// Compiled `chat_filters` from the channel settings, by channel.
+    chat_filters: HashMap<String, RegexSet>,
+    offline_queue: HashMap<String, VecDeque<(Instant, integrations::OutgoingMessage)>>,
+    /// The last few chat messages in each channel, oldest first.
+    recent_chat: HashMap<String, VecDeque<RecentChat>>,
+    /// While set, statistics uploads are rejected so that the database can be worked on.
+    maintenance: bool,
+    /// Errors reported before the Discord client connected, to be sent once it does.
+    pending_errors: Vec<BackendError>,
 }
 
 impl Controller {
-    pub async fn new(config: Config) -> Controller {
-        Controller {
+    pub async fn new(config: Config) -> Result<Controller, ConfigError> {
+        let chat_filters = config.chat_filters()?;
+
+        Ok(Controller {
             config,
             discord: None,
             database: None,
             statistics: None,
+            mojang_client: None,
             integration_clients: HashMap::new(),
             status_by_channel: HashMap::new(),
+            known_channels: HashSet::new(),
+            client_info: HashMap::new(),
+            chat_filters,
+            offline_queue: HashMap::new(),
+            recent_chat: HashMap::new(),
+            maintenance: false,
+            pending_errors: Vec::new(),
+        })
+    }
+
+    /// Queues a message for a known channel whose server is currently offline, returning whether
+    /// it was queued.
+    fn queue_offline(&mut self, channel: &str, message: integrations::OutgoingMessage) -> bool {
+        let config = match &self.config.offline_queue {
+            Some(config) => config,
+            None => return false,
+        };
+        if let integrations::OutgoingMessage::Chat(_) = message {
+            if !config.include_chat {
+                return false;
+            }
+        }
+        // Only queue for channels that have connected before so that typos aren't kept around
+        if !self.known_channels.contains(channel) {
+            return false;
+        }
+
+        let queue = self.offline_queue.entry(channel.to_owned()).or_default();
+        if queue.len() >= config.max_messages {
+            warn!(
+                "offline queue for channel '{}' is full, dropping the oldest message",
+                channel
+            );
+            queue.pop_front();
+        }
+        queue.push_back((Instant::now(), message));
+        true
+    }
+
+    async fn flush_offline_queue(&mut self, channel: &str, client: &Address<IntegrationsClient>) {
+        let (queue, config) = match (
+            self.offline_queue.remove(channel),
+            &self.config.offline_queue,
+        ) {
+            (Some(queue), Some(config)) => (queue, config),
+            _ => return,
+        };
+
+        let ttl = Duration::from_secs(config.ttl_seconds);
+        let messages = queue
+            .into_iter()
+            .filter(|(queued_at, _)| queued_at.elapsed() < ttl)
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+
+        info!(
+            "delivering {} queued messages to channel '{}'",
+            messages.len(),
+            channel
+        );
+        for message in messages {
+            let _ = client.send(message).await;
+        }
+    }
+
+    /// Logs a channel's status after it changed, and passes it on to Discord and the database.
+    async fn publish_status(&self, channel: &str) {
+        let Some(status) = self.status_by_channel.get(channel) else {
+            return;
+        };
+
+        println!(
+            "[{}] {} games, {} players",
+            channel,
+            status.games.len(),
+            status.players.len()
+        );
+
+        if let Some(discord) = &self.discord {
+            let _ = discord
+                .send(discord::UpdateRelayStatus {
+                    channel: channel.to_owned(),
+                    game_version: self
+                        .config
+                        .display_game_version(channel, &status.game_version)
+                        .to_owned(),
+                    server_ip: status.server_ip.clone(),
+                    player_count: status.players.len(),
+                })
+                .await;
+        }
+
+        if let Some(database) = &self.database {
+            let _ = database
+                .send(database::WriteStatus {
+                    channel: channel.to_owned(),
+                    time: SystemTime::now(),
+                    status: status.clone(),
+                })
+                .await;
+        }
+    }
+
+    /// A channel's status as it should be shown, with any configured display overrides applied.
+    fn displayed_status(&self, channel: &str, status: &ServerStatus) -> ServerStatus {
+        let mut status = status.clone();
+        status.game_version = self
+            .config
+            .display_game_version(channel, &status.game_version)
+            .to_owned();
+        status
+    }
+
+    fn record_chat(&mut self, channel: &str, sender: &str, content: &str, source: ChatSource) {
+        let recent_chat = self.recent_chat.entry(channel.to_owned()).or_default();
+        if recent_chat.len() >= MAX_RECENT_CHAT {
+            recent_chat.pop_front();
+        }
+        recent_chat.push_back(RecentChat {
+            sender: sender.to_owned(),
+            content: content.to_owned(),
+            source,
+            time: Utc::now(),
+        });
+    }
+
+    /// Sends a command to a channel's server, queueing it if the server is offline. Returns
+    /// whether the command was sent or queued.
+    async fn send_command(&mut self, message: OutgoingCommand) -> bool {
+        println!(
+            "[{}] <@{}> /{}",
+            message.channel, message.sender, message.command
+        );
+
+        let outgoing = integrations::OutgoingMessage::Command {
+            command: message.command,
+            sender: message.sender,
+            roles: message.roles,
+            silent: message.silent,
+        };
+        if let Some(integrations) = self.integration_clients.get(&message.channel) {
+            let _ = integrations.send(outgoing).await;
+            true
+        } else {
+            self.queue_offline(&message.channel, outgoing)
         }
     }
 }
@@ -38,9 +209,28 @@ pub struct RegisterIntegrationsClient {
     pub channel: String,
     pub game_version: String,
     pub server_ip: Option<String>,
+    pub server_type: ServerType,
+    pub protocol_version: Option<u32>,
     pub client: Address<IntegrationsClient>,
 }
 
+/// Details about a connected integrations client, as reported in its handshake.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub protocol_version: Option<u32>,
+    pub server_type: ServerType,
+    pub game_version: String,
+    pub connected_at: Instant,
+}
+
+pub struct GetClientInfo(pub String);
+
+/// Gets up to `limit` of the most recent chat messages in a channel, oldest first.
+pub struct GetRecentChat {
+    pub channel: String,
+    pub limit: usize,
+}
+
 pub struct UnregisterIntegrationsClient {
     pub channel: String,
 }
@@ -61,6 +251,36 @@ pub struct RegisterStatisticsDatabaseController {
 
 pub struct GetStatisticsDatabaseController;
 
+pub struct RegisterMojangApiClient {
+    pub client: Address<MojangApiClient>,
+}
+
+pub struct GetMojangApiClient;
+
+pub struct GetCapabilities;
+
+/// Which optional subsystems are currently available.
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    pub statistics: bool,
+    pub discord: bool,
+    pub database: bool,
+}
+
+pub struct SetMaintenance(pub bool);
+
+pub struct GetMaintenance;
+
+#[derive(Error, Debug)]
+pub enum UploadRejected {
+    #[error("statistics uploads are disabled for maintenance")]
+    Maintenance,
+    #[error("statistics are not enabled on this backend")]
+    Unavailable,
+    #[error("{0}")]
+    Failed(#[from] StatisticsDatabaseError),
+}
+
 pub struct IncomingChat {
     pub channel: String,
     pub sender: Player,
@@ -80,6 +300,13 @@ pub struct OutgoingCommand {
     pub silent: bool,
 }
 
+/// Sends a command to every connected server, returning how many received it.
+pub struct BroadcastCommand {
+    pub command: String,
+    pub sender: String,
+    pub roles: Vec<String>,
+}
+
 pub struct OutgoingServerChange {
     // This should always be sent to a proxy, never a regular server.
     pub channel: String,
@@ -93,6 +320,22 @@ pub struct StatusUpdate {
     pub players: Option<Vec<Player>>,
 }
 
+/// Changes part of a server's status, leaving everything else as it is. Games are identified by
+/// name and players by id; adding one that is already present replaces it.
+pub struct StatusDelta {
+    pub channel: String,
+    pub add_games: Vec<Game>,
+    pub remove_games: Vec<String>,
+    pub add_players: Vec<Player>,
+    pub remove_players: Vec<String>,
+}
+
+pub struct ServerInfoUpdate {
+    pub channel: String,
+    pub server_ip: Option<String>,
+    pub game_version: Option<String>,
+}
+
 pub struct PerformanceUpdate {
     pub channel: String,
     pub performance: ServerPerformance,
@@ -116,22 +359,88 @@ pub struct ServerSystemMessage {
 
 pub struct GetStatus(pub String);
 
+pub struct GetAllStatuses;
+
+pub struct GetChannelConfig(pub String);
+
+/// How many errors to hold on to while waiting for the Discord client to connect.
+const MAX_PENDING_ERRORS: usize = 16;
+
+/// How many chat messages are kept per channel for fetching over HTTP.
+pub const MAX_RECENT_CHAT: usize = 100;
+
 pub struct BackendError {
+    pub severity: ErrorSeverity,
     pub title: String,
     pub description: String,
     pub fields: Option<HashMap<String, String>>,
 }
 
+impl From<BackendError> for ReportError {
+    fn from(error: BackendError) -> Self {
+        ReportError {
+            severity: error.severity,
+            title: error.title,
+            description: error.description,
+            fields: error.fields,
+        }
+    }
+}
+
 impl Handler<RegisterIntegrationsClient> for Controller {
-    type Return = ();
+    /// Whether the client was registered. A second server connecting with the same channel as a
+    /// live client is rejected, since that is almost always a misconfiguration.
+    type Return = bool;
 
-    async fn handle(&mut self, message: RegisterIntegrationsClient, _ctx: &mut Context<Self>) {
+    async fn handle(
+        &mut self,
+        message: RegisterIntegrationsClient,
+        _ctx: &mut Context<Self>,
+    ) -> bool {
+        if let Some(existing) = self.integration_clients.get(&message.channel) {
+            if existing.is_connected() {
+                warn!(
+                    "rejecting integrations client for channel '{}': another server is already connected on it",
+                    message.channel
+                );
+                if let Some(discord) = &self.discord {
+                    let _ = discord
+                        .send(ReportError {
+                            severity: ErrorSeverity::Warning,
+                            title: "Duplicate integrations channel".to_owned(),
+                            description: format!(
+                                "A server tried to connect to channel `{}`, which is already in use by another server. Check the channel names in the integrations configs.",
+                                message.channel
+                            ),
+                            fields: None,
+                        })
+                        .await;
+                }
+                return false;
+            }
+        }
+
+        self.flush_offline_queue(&message.channel, &message.client)
+            .await;
         self.integration_clients
             .insert(message.channel.clone(), message.client);
+        self.known_channels.insert(message.channel.clone());
+
+        self.client_info.insert(
+            message.channel.clone(),
+            ClientInfo {
+                protocol_version: message.protocol_version,
+                server_type: message.server_type,
+                game_version: message.game_version.clone(),
+                connected_at: Instant::now(),
+            },
+        );
 
         let status = self.status_by_channel.entry(message.channel).or_default();
         status.game_version = message.game_version;
         status.server_ip = message.server_ip;
+
+        true
     }
 }
 
@@ -140,6 +449,29 @@ impl Handler<UnregisterIntegrationsClient> for Controller {
 
     async fn handle(&mut self, message: UnregisterIntegrationsClient, _ctx: &mut Context<Self>) {
         self.integration_clients.remove(&message.channel);
+        self.client_info.remove(&message.channel);
+    }
+}
+
+impl Handler<GetRecentChat> for Controller {
+    type Return = Vec<RecentChat>;
+
+    async fn handle(&mut self, message: GetRecentChat, _ctx: &mut Context<Self>) -> Self::Return {
+        match self.recent_chat.get(&message.channel) {
+            Some(recent_chat) => {
+                let skip = recent_chat.len().saturating_sub(message.limit);
+                recent_chat.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Handler<GetClientInfo> for Controller {
+    type Return = Option<ClientInfo>;
+
+    async fn handle(&mut self, message: GetClientInfo, _ctx: &mut Context<Self>) -> Self::Return {
+        self.client_info.get(&message.0).cloned()
     }
 }
 
@@ -147,6 +479,9 @@ impl Handler<RegisterDiscordClient> for Controller {
     type Return = ();
 
     async fn handle(&mut self, message: RegisterDiscordClient, _ctx: &mut Context<Self>) {
+        for error in self.pending_errors.drain(..) {
+            let _ = message.client.send(ReportError::from(error)).await;
+        }
         self.discord = Some(message.client);
     }
 }
@@ -191,6 +526,70 @@ impl Handler<GetStatisticsDatabaseController> for Controller {
     }
 }
 
+impl Handler<RegisterMojangApiClient> for Controller {
+    type Return = ();
+
+    async fn handle(&mut self, message: RegisterMojangApiClient, _ctx: &mut Context<Self>) {
+        self.mojang_client = Some(message.client);
+    }
+}
+
+impl Handler<GetMojangApiClient> for Controller {
+    type Return = Option<Address<MojangApiClient>>;
+
+    async fn handle(
+        &mut self,
+        _message: GetMojangApiClient,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        self.mojang_client.clone()
+    }
+}
+
+impl Handler<GetCapabilities> for Controller {
+    type Return = Capabilities;
+
+    async fn handle(
+        &mut self,
+        _message: GetCapabilities,
+        _ctx: &mut Context<Self>,
+    ) -> Capabilities {
+        Capabilities {
+            statistics: self.statistics.is_some(),
+            discord: self
+                .discord
+                .as_ref()
+                .is_some_and(|discord| discord.is_connected()),
+            database: self
+                .database
+                .as_ref()
+                .is_some_and(|database| database.is_connected()),
+        }
+    }
+}
+
+impl Handler<SetMaintenance> for Controller {
+    type Return = ();
+
+    async fn handle(&mut self, message: SetMaintenance, _ctx: &mut Context<Self>) {
+        if self.maintenance != message.0 {
+            info!(
+                "maintenance mode {}",
+                if message.0 { "enabled" } else { "disabled" }
+            );
+        }
+        self.maintenance = message.0;
+    }
+}
+
+impl Handler<GetMaintenance> for Controller {
+    type Return = bool;
+
+    async fn handle(&mut self, _message: GetMaintenance, _ctx: &mut Context<Self>) -> bool {
+        self.maintenance
+    }
+}
+
 impl Handler<IncomingChat> for Controller {
     type Return = ();
 
@@ -200,9 +599,31 @@ impl Handler<IncomingChat> for Controller {
             message.channel, message.sender.name, message.content
         );
 
+        let filtered = self
+            .chat_filters
+            .get(&message.channel)
+            .is_some_and(|filter| filter.is_match(&message.content));
+        if filtered {
+            debug!(
+                "dropping filtered chat message from {} in {}",
+                message.sender.name, message.channel
+            );
+            return;
+        }
+
+        self.record_chat(
+            &message.channel,
+            &message.sender.name,
+            &message.content,
+            ChatSource::Minecraft,
+        );
+
         if let Some(discord) = &self.discord {
             let _ = discord
                 .send(discord::SendChat {
+                    username: self
+                        .config
+                        .relay_username(&message.channel, &message.sender.name),
                     channel: message.channel,
                     sender: message.sender,
                     content: message.content,
@@ -220,11 +641,18 @@ impl Handler<OutgoingChat> for Controller {
             "[{}] <@{}> {}",
             message.channel, message.chat.sender, message.chat.content
         );
+        self.record_chat(
+            &message.channel,
+            &message.chat.sender,
+            &message.chat.content,
+            ChatSource::Discord,
+        );
 
+        let outgoing = integrations::OutgoingMessage::Chat(message.chat);
         if let Some(integrations) = self.integration_clients.get(&message.channel) {
-            let _ = integrations
-                .send(integrations::OutgoingMessage::Chat(message.chat))
-                .await;
+            let _ = integrations.send(outgoing).await;
+        } else {
+            self.queue_offline(&message.channel, outgoing);
         }
     }
 }
@@ -233,24 +661,34 @@ impl Handler<OutgoingCommand> for Controller {
     type Return = bool;
 
     async fn handle(&mut self, message: OutgoingCommand, _ctx: &mut Context<Self>) -> Self::Return {
-        println!(
-            "[{}] <@{}> /{}",
-            message.channel, message.sender, message.command
-        );
+        self.send_command(message).await
+    }
+}
 
-        if let Some(integrations) = self.integration_clients.get(&message.channel) {
-            let _ = integrations
-                .send(integrations::OutgoingMessage::Command {
-                    command: message.command,
-                    sender: message.sender,
-                    roles: message.roles,
-                    silent: message.silent,
-                })
-                .await;
-            true
-        } else {
-            false
+impl Handler<BroadcastCommand> for Controller {
+    type Return = usize;
+
+    async fn handle(
+        &mut self,
+        message: BroadcastCommand,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        println!("[*] <@{}> /{}", message.sender, message.command);
+
+        let mut received = 0;
+        for integrations in self.integration_clients.values() {
+            let outgoing = integrations::OutgoingMessage::Command {
+                command: message.command.clone(),
+                sender: message.sender.clone(),
+                roles: message.roles.clone(),
+                silent: true,
+            };
+            if integrations.send(outgoing).await.is_ok() {
+                received += 1;
+            }
         }
+
+        received
     }
 }
 
@@ -277,50 +715,93 @@ impl Handler<OutgoingServerChange> for Controller {
     }
 }
 
-impl Handler<StatusUpdate> for Controller {
+impl Handler<ServerInfoUpdate> for Controller {
     type Return = ();
 
-    async fn handle(&mut self, message: StatusUpdate, _ctx: &mut Context<Self>) {
+    async fn handle(&mut self, message: ServerInfoUpdate, _ctx: &mut Context<Self>) {
         let status = self
             .status_by_channel
             .entry(message.channel.clone())
             .or_default();
 
-        if let Some(games) = message.games {
-            status.games = games;
+        if let Some(server_ip) = message.server_ip {
+            status.server_ip = Some(server_ip);
         }
 
-        if let Some(players) = message.players {
-            status.players = players;
+        if let Some(game_version) = message.game_version {
+            if let Some(info) = self.client_info.get_mut(&message.channel) {
+                info.game_version = game_version.clone();
+            }
+            status.game_version = game_version;
         }
 
-        println!(
-            "[{}] {} games, {} players",
-            message.channel,
-            status.games.len(),
-            status.players.len()
-        );
-
         if let Some(discord) = &self.discord {
             let _ = discord
                 .send(discord::UpdateRelayStatus {
-                    channel: message.channel.clone(),
-                    game_version: status.game_version.clone(),
+                    game_version: self
+                        .config
+                        .display_game_version(&message.channel, &status.game_version)
+                        .to_owned(),
+                    channel: message.channel,
                     server_ip: status.server_ip.clone(),
                     player_count: status.players.len(),
                 })
                 .await;
         }
+    }
+}
 
-        if let Some(database) = &self.database {
-            let _ = database
-                .send(database::WriteStatus {
-                    channel: message.channel.clone(),
-                    time: SystemTime::now(),
-                    status: status.clone(),
-                })
-                .await;
+impl Handler<StatusUpdate> for Controller {
+    type Return = ();
+
+    async fn handle(&mut self, message: StatusUpdate, _ctx: &mut Context<Self>) {
+        let status = self
+            .status_by_channel
+            .entry(message.channel.clone())
+            .or_default();
+
+        if let Some(games) = message.games {
+            status.games = games;
+        }
+
+        if let Some(players) = message.players {
+            status.players = players;
         }
+
+        self.publish_status(&message.channel).await;
+    }
+}
+
+impl Handler<StatusDelta> for Controller {
+    type Return = ();
+
+    async fn handle(&mut self, message: StatusDelta, _ctx: &mut Context<Self>) {
+        let status = self
+            .status_by_channel
+            .entry(message.channel.clone())
+            .or_default();
+
+        let remove_games = message
+            .remove_games
+            .iter()
+            .chain(message.add_games.iter().map(|game| &game.name))
+            .collect::<HashSet<_>>();
+        status
+            .games
+            .retain(|game| !remove_games.contains(&game.name));
+        status.games.extend(message.add_games);
+
+        let remove_players = message
+            .remove_players
+            .iter()
+            .chain(message.add_players.iter().map(|player| &player.id))
+            .collect::<HashSet<_>>();
+        status
+            .players
+            .retain(|player| !remove_players.contains(&player.id));
+        status.players.extend(message.add_players);
+
+        self.publish_status(&message.channel).await;
     }
 }
 
@@ -346,7 +827,21 @@ impl Handler<ServerLifecycleStart> for Controller {
     async fn handle(&mut self, message: ServerLifecycleStart, _ctx: &mut Context<Self>) {
         println!("[{}] started", message.channel);
 
-        if let Some(discord) = &self.discord {
+        // The server is registered by the time it reports starting, so these won't be queued
+        let commands = self.config.on_start_commands(&message.channel).to_vec();
+        for command in commands {
+            self.send_command(OutgoingCommand {
+                channel: message.channel.clone(),
+                sender: "Backend".to_owned(),
+                command,
+                roles: Vec::new(),
+                silent: true,
+            })
+            .await;
+        }
+
+        let relay_lifecycle = self.config.relays_lifecycle(&message.channel);
+        if let Some(discord) = self.discord.as_ref().filter(|_| relay_lifecycle) {
             let _ = discord
                 .send(discord::SendSystem {
                     channel: message.channel.clone(),
@@ -381,7 +876,8 @@ impl Handler<ServerLifecycleStop> for Controller {
         println!("[{}] stopped", message.channel);
         self.status_by_channel.remove(&message.channel);
 
-        if let Some(discord) = &self.discord {
+        let relay_lifecycle = self.config.relays_lifecycle(&message.channel);
+        if let Some(discord) = self.discord.as_ref().filter(|_| relay_lifecycle) {
             let content = if message.crash {
                 format!(
                     "{} has crashed!",
@@ -435,7 +931,47 @@ impl Handler<GetStatus> for Controller {
         message: GetStatus,
         _ctx: &mut Context<Self>,
     ) -> Option<ServerStatus> {
-        self.status_by_channel.get(&message.0).cloned()
+        self.status_by_channel
+            .get(&message.0)
+            .map(|status| self.displayed_status(&message.0, status))
+    }
+}
+
+impl Handler<GetAllStatuses> for Controller {
+    type Return = HashMap<String, ServerStatus>;
+
+    async fn handle(
+        &mut self,
+        _message: GetAllStatuses,
+        _ctx: &mut Context<Self>,
+    ) -> HashMap<String, ServerStatus> {
+        self.status_by_channel
+            .iter()
+            .map(|(channel, status)| (channel.clone(), self.displayed_status(channel, status)))
+            .collect()
+    }
+}
+
+impl Handler<GetChannelConfig> for Controller {
+    type Return = ChannelConfig;
+
+    async fn handle(
+        &mut self,
+        message: GetChannelConfig,
+        _ctx: &mut Context<Self>,
+    ) -> ChannelConfig {
+        let relay_attached = match &self.discord {
+            Some(discord) => discord
+                .send(discord::IsRelayAttached(message.0.clone()))
+                .await
+                .unwrap_or(false),
+            None => false,
+        };
+
+        ChannelConfig {
+            relay_attached,
+            kickback: self.config.kickbacks.get(&message.0).cloned(),
+        }
     }
 }
 
@@ -444,30 +980,37 @@ impl Handler<BackendError> for Controller {
 
     async fn handle(&mut self, message: BackendError, _ctx: &mut Context<Self>) {
         if let Some(discord) = &self.discord {
-            let _ = discord
-                .send(ReportError {
-                    title: message.title,
-                    description: message.description,
-                    fields: message.fields,
-                })
-                .await;
+            let _ = discord.send(ReportError::from(message)).await;
+        } else if self.config.discord.is_some() && self.pending_errors.len() < MAX_PENDING_ERRORS {
+            // Errors during startup can happen before Discord has connected
+            self.pending_errors.push(message);
         }
     }
 }
 
 impl Handler<UploadStatsBundle> for Controller {
-    type Return = ();
+    type Return = Result<Option<GameStatsBundle>, UploadRejected>;
 
     async fn handle(
         &mut self,
         message: UploadStatsBundle,
         _ctx: &mut Context<Self>,
     ) -> Self::Return {
+        if self.maintenance {
+            warn!(
+                "rejecting statistics upload for game {} from '{}' during maintenance",
+                message.game_id, message.server
+            );
+            return Err(UploadRejected::Maintenance);
+        }
+
         if let Some(statistics) = &self.statistics {
-            statistics
+            Ok(statistics
                 .send(message)
                 .await
-                .expect("statistics controller disconnected")
+                .expect("statistics controller disconnected")?)
+        } else {
+            Err(UploadRejected::Unavailable)
         }
     }
 }
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
-use serenity::all::{Cache, CreateEmbed, ExecuteWebhook, Http, Webhook};
+use serenity::all::{
+    Cache, ChannelId, CreateEmbed, CreateMessage, CreateWebhook, ExecuteWebhook, Http, HttpError,
+    StatusCode, Webhook,
+};
 use serenity::client::Context as SerenityContext;
 use serenity::model::channel::{Message, Reaction};
 use serenity::{async_trait, prelude::*};
@@ -14,6 +18,7 @@ use crate::model::*;
 use crate::{DiscordConfig, Persistent};
 
 mod lfp;
+mod parser;
 mod pings;
 mod relay;
 
@@ -44,6 +49,8 @@ pub struct DiscordClient {
     config: DiscordConfig,
     cache_and_http: Option<CacheAndHttp>,
     data: Option<Arc<RwLock<TypeMap>>>,
+    /// Messages waiting to be sent to each relay, keyed by integrations channel.
+    relay_queues: HashMap<String, tokio::sync::mpsc::UnboundedSender<relay::RelayMessage>>,
 }
 
 impl Actor for DiscordClient {
@@ -64,10 +71,12 @@ pub async fn run(controller: Address<Controller>, config: DiscordConfig) {
         config: config.clone(),
         cache_and_http: None,
         data: None,
+        relay_queues: HashMap::new(),
     };
     let address = xtra::spawn_tokio(actor, Mailbox::unbounded());
 
     let handler = DiscordHandler {
+        controller: controller.clone(),
         pings: pings::Handler {
             discord: address.clone(),
         },
@@ -79,10 +88,19 @@ pub async fn run(controller: Address<Controller>, config: DiscordConfig) {
         },
     };
 
-    let intents = GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT
-        | GatewayIntents::GUILDS
-        | GatewayIntents::GUILD_MESSAGE_REACTIONS;
+    let intents = match &config.intents {
+        Some(names) => names.iter().fold(GatewayIntents::empty(), |intents, name| {
+            let intent = GatewayIntents::from_name(name)
+                .unwrap_or_else(|| panic!("unknown gateway intent '{}'", name));
+            intents | intent
+        }),
+        None => {
+            GatewayIntents::GUILD_MESSAGES
+                | GatewayIntents::MESSAGE_CONTENT
+                | GatewayIntents::GUILDS
+                | GatewayIntents::GUILD_MESSAGE_REACTIONS
+        }
+    };
 
     let mut client = Client::builder(config.token, intents)
         .event_handler(handler)
@@ -96,6 +114,8 @@ pub async fn run(controller: Address<Controller>, config: DiscordConfig) {
         data.insert::<lfp::StoreKey>(lfp_store);
     }
 
+    lfp::resume_pending_deletions(client.http.clone(), client.data.clone()).await;
+
     address
         .send(Init {
             cache_and_http: CacheAndHttp {
@@ -123,6 +143,8 @@ struct Init {
 pub struct SendChat {
     pub channel: String,
     pub sender: Player,
+    /// The webhook username to relay the message with, following the channel's template.
+    pub username: String,
     pub content: String,
 }
 
@@ -145,7 +167,10 @@ pub struct UpdateRelayStatus {
     pub player_count: usize,
 }
 
+pub struct IsRelayAttached(pub String);
+
 pub struct ReportError {
+    pub severity: ErrorSeverity,
     pub title: String,
     pub description: String,
     pub fields: Option<HashMap<String, String>>,
@@ -192,13 +217,22 @@ impl Handler<UpdateRelayStatus> for DiscordClient {
     }
 }
 
+impl Handler<IsRelayAttached> for DiscordClient {
+    type Return = bool;
+
+    async fn handle(&mut self, message: IsRelayAttached, _ctx: &mut XtraContext<Self>) -> bool {
+        relay::is_attached(self, &message.0).await
+    }
+}
+
 impl Handler<ReportError> for DiscordClient {
     type Return = ();
 
     async fn handle(&mut self, message: ReportError, _ctx: &mut XtraContext<Self>) {
-        if let (Some(cache_and_http), Some(webhook_config)) =
-            (&self.cache_and_http, &self.config.error_webhook)
-        {
+        if let (Some(cache_and_http), Some(webhook_config)) = (
+            &self.cache_and_http,
+            self.config.error_webhook_for(message.severity),
+        ) {
             if let Ok(webhook) = Webhook::from_id_with_token(
                 cache_and_http,
                 webhook_config.id,
@@ -239,25 +273,53 @@ impl Handler<ReportError> for DiscordClient {
 }
 
 struct DiscordHandler {
+    controller: Address<Controller>,
     pings: pings::Handler,
     relay: relay::Handler,
     lfp: lfp::Handler,
 }
 
 impl DiscordHandler {
-    async fn handle_command(&self, tokens: &[&str], ctx: &SerenityContext, message: &Message) {
+    async fn handle_command(&self, ctx: &SerenityContext, message: &Message) {
+        let result = match parser::parse_command(&message.content) {
+            Some(tokens) => {
+                let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+                self.run_command(&tokens[1..], ctx, message).await
+            }
+            None => Err(CommandError::UnterminatedQuote),
+        };
+
+        let reaction = if result.is_ok() { '✅' } else { '❌' };
+        let _ = message.react(&ctx, reaction).await;
+
+        if let Err(err) = result {
+            let _ = message.reply(&ctx, err.to_string()).await;
+        }
+    }
+
+    async fn run_command(
+        &self,
+        tokens: &[&str],
+        ctx: &SerenityContext,
+        message: &Message,
+    ) -> CommandResult {
         let admin = check_message_admin(ctx, message).await;
 
-        let result = match tokens {
+        match tokens {
             ["relay", "connect", channel] if admin => {
                 self.relay.connect(channel, ctx, message).await
             }
             ["relay", "disconnect"] if admin => self.relay.disconnect(ctx, message).await,
+            ["relay", "cleanup"] if admin => self.relay.cleanup(ctx, message).await,
             ["relay", "command", channel, command @ ..] if admin => {
                 self.relay
                     .send_relay_command(ctx, message, channel, command)
                     .await
             }
+            ["relay", "broadcast", command @ ..] if admin && !command.is_empty() => {
+                self.relay.broadcast_command(ctx, message, command).await
+            }
+            ["status", "info", channel] if admin => self.status_info(ctx, message, channel).await,
             ["ping", "add", ping, role] if admin => self.pings.add(ctx, message, ping, role).await,
             ["ping", "remove", ping] if admin => self.pings.remove(ctx, message, ping).await,
             ["ping", "allow", ping, role] if admin => {
@@ -268,15 +330,54 @@ impl DiscordHandler {
             }
             ["ping", "request", ping, ..] => self.pings.request(ctx, message, ping).await,
             ["lfp", "setup", ..] => self.lfp.setup_for_channel(ctx, message).await,
+            ["lfp", "list"] => self.lfp.list(ctx, message).await,
             _ => Err(CommandError::InvalidCommand),
-        };
+        }
+    }
 
-        let reaction = if result.is_ok() { '✅' } else { '❌' };
-        let _ = message.react(&ctx, reaction).await;
+    /// Replies with what a connected server reported about itself in its handshake.
+    async fn status_info(
+        &self,
+        ctx: &SerenityContext,
+        message: &Message,
+        channel: &str,
+    ) -> CommandResult {
+        let info = self
+            .controller
+            .send(GetClientInfo(channel.to_owned()))
+            .await
+            .expect("controller disconnected")
+            .ok_or(CommandError::ChannelNotOnline)?;
+
+        let protocol_version = info
+            .protocol_version
+            .map_or_else(|| "unversioned".to_owned(), |version| version.to_string());
+        let uptime = info.connected_at.elapsed().as_secs();
+
+        let embed = CreateEmbed::new()
+            .title(format!("Status of `{}`", channel))
+            .field("Protocol version", protocol_version, true)
+            .field("Server type", format!("{:?}", info.server_type), true)
+            .field("Game version", info.game_version, true)
+            .field(
+                "Uptime",
+                format!(
+                    "{}h {}m {}s",
+                    uptime / 3600,
+                    (uptime / 60) % 60,
+                    uptime % 60
+                ),
+                true,
+            );
+        message
+            .channel_id
+            .send_message(
+                ctx,
+                CreateMessage::new().embed(embed).reference_message(message),
+            )
+            .await?;
 
-        if let Err(err) = result {
-            let _ = message.reply(&ctx, err.to_string()).await;
-        }
+        Ok(())
     }
 }
 
@@ -285,8 +386,7 @@ impl EventHandler for DiscordHandler {
     async fn message(&self, ctx: SerenityContext, message: Message) {
         if !message.author.bot {
             if let Ok(true) = message.mentions_me(&ctx).await {
-                let tokens: Vec<&str> = message.content.split_ascii_whitespace().collect();
-                self.handle_command(&tokens[1..], &ctx, &message).await;
+                self.handle_command(&ctx, &message).await;
             } else if message.content.starts_with("//") {
                 self.relay.send_outgoing_command(&ctx, &message).await;
             } else {
@@ -318,6 +418,55 @@ async fn check_message_admin(ctx: &SerenityContext, message: &Message) -> bool {
     false
 }
 
+const WEBHOOK_CREATE_ATTEMPTS: u32 = 3;
+const WEBHOOK_CREATE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Creates a webhook in the given channel, retrying with backoff if Discord fails transiently.
+async fn create_webhook_with_retry(
+    http: &Http,
+    channel: ChannelId,
+    builder: CreateWebhook<'_>,
+) -> serenity::Result<Webhook> {
+    let mut attempt = 1;
+    loop {
+        match channel.create_webhook(http, builder.clone()).await {
+            Err(err) if attempt < WEBHOOK_CREATE_ATTEMPTS && is_transient_error(&err) => {
+                warn!(
+                    "failed to create webhook in {} (attempt {}/{}), retrying: {}",
+                    channel, attempt, WEBHOOK_CREATE_ATTEMPTS, err
+                );
+                tokio::time::sleep(WEBHOOK_CREATE_BACKOFF * 2_u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The JSON error code Discord responds with when a webhook no longer exists.
+const UNKNOWN_WEBHOOK_CODE: isize = 10015;
+
+/// Checks whether an error is caused by a webhook having been deleted on Discord's side.
+fn is_unknown_webhook_error(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            response.status_code == StatusCode::NOT_FOUND
+                || response.error.code == UNKNOWN_WEBHOOK_CODE
+        }
+        _ => false,
+    }
+}
+
+fn is_transient_error(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            response.status_code.is_server_error()
+        }
+        serenity::Error::Http(HttpError::Request(_)) => true,
+        _ => false,
+    }
+}
+
 pub type CommandResult = std::result::Result<(), CommandError>;
 
 #[derive(thiserror::Error, Debug)]
@@ -346,4 +495,12 @@ pub enum CommandError {
     MustMentionRole,
     #[error("The channel with that name does not exist!")]
     ChannelDoesNotExist,
+    #[error("No server is connected to that channel!")]
+    ChannelNotOnline,
+    #[error("Unterminated quote in command!")]
+    UnterminatedQuote,
+    #[error("The configured LFP reaction is not a valid emoji!")]
+    InvalidLfpReaction,
+    #[error("This channel is not set up for looking for players!")]
+    LfpNotSetUp,
 }
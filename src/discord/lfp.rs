@@ -1,7 +1,9 @@
 use std::time::{Duration, SystemTime};
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serenity::all::{CreateMessage, CreateWebhook, ExecuteWebhook, GuildId};
+use serenity::all::{
+    CreateEmbed, CreateMessage, CreateWebhook, EmojiId, ExecuteWebhook, GuildId, ReactionType,
+};
 use serenity::model::channel::Channel as SerenityChannel;
 use serenity::model::id::{ChannelId, MessageId, RoleId, UserId};
 use serenity::model::webhook::Webhook;
@@ -9,10 +11,30 @@ use serenity::model::webhook::Webhook;
 use super::*;
 
 // TODO: this code is really, really bad!
-//       we can also make improvement to behavior by NOT deleting users' messages until that
-//       10 minute time period has passed. That way you cannot hide a ping that you sent.
 
-const REACTION: char = '👋';
+const DEFAULT_REACTION: char = '👋';
+
+/// How many registrations are listed at most, to stay within Discord's embed length limit.
+const MAX_LISTED_REGISTRATIONS: usize = 50;
+
+/// Parses a unicode emoji, a custom emoji as `<:name:id>`, or a custom emoji id.
+fn parse_reaction(reaction: &str) -> Option<ReactionType> {
+    let reaction = reaction.trim();
+    match reaction.parse::<u64>() {
+        Ok(0) => None,
+        Ok(id) => Some(EmojiId::new(id).into()),
+        Err(_) => reaction.parse::<ReactionType>().ok(),
+    }
+}
+
+/// Compares emoji by id if they are custom, as the name of a custom emoji can change.
+fn is_same_reaction(a: &ReactionType, b: &ReactionType) -> bool {
+    match (a, b) {
+        (ReactionType::Custom { id: a, .. }, ReactionType::Custom { id: b, .. }) => a == b,
+        (ReactionType::Unicode(a), ReactionType::Unicode(b)) => a == b,
+        _ => false,
+    }
+}
 
 pub struct StoreKey;
 
@@ -24,6 +46,57 @@ impl TypeMapKey for StoreKey {
 pub struct Store {
     channels: ChannelMap,
     last_ping_time: Option<SystemTime>,
+    #[serde(default)]
+    pending_deletions: Vec<PendingDeletion>,
+}
+
+/// The message of a removed registration, which is kept up until its cooldown is over. These are
+/// stored so that the message is still deleted if the backend restarts in the meantime.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+struct PendingDeletion {
+    channel_id: u64,
+    message_id: u64,
+    delete_at: SystemTime,
+}
+
+/// Schedules the deletion of messages that were still waiting out their cooldown when the
+/// backend last stopped.
+pub async fn resume_pending_deletions(http: Arc<Http>, data: Arc<RwLock<TypeMap>>) {
+    let pending = {
+        let data = data.read().await;
+        data.get::<StoreKey>().unwrap().pending_deletions.clone()
+    };
+    for deletion in pending {
+        schedule_deletion(http.clone(), data.clone(), deletion);
+    }
+}
+
+fn schedule_deletion(http: Arc<Http>, data: Arc<RwLock<TypeMap>>, deletion: PendingDeletion) {
+    tokio::spawn(async move {
+        let delay = deletion
+            .delete_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        if let Err(err) = ChannelId::new(deletion.channel_id)
+            .delete_message(&http, deletion.message_id)
+            .await
+        {
+            error!("Failed to delete looking-for-player message: {:?}", err);
+        }
+
+        // Forgotten even if deleting failed, since the message is most likely gone already
+        let mut data = data.write().await;
+        let store = data.get_mut::<StoreKey>().unwrap();
+        store
+            .write(|store| {
+                store
+                    .pending_deletions
+                    .retain(|pending| *pending != deletion)
+            })
+            .await;
+    });
 }
 
 impl Store {
@@ -51,7 +124,7 @@ impl Store {
 
         let can_ping = match self.last_ping_time {
             Some(last_ping_time) => {
-                let interval = Duration::from_secs(config.lfp_ping_interval_minutes as u64 * 60);
+                let interval = ping_interval(config);
                 matches!(now.duration_since(last_ping_time), Ok(duration) if duration > interval)
             }
             None => true,
@@ -66,6 +139,10 @@ impl Store {
     }
 }
 
+fn ping_interval(config: &DiscordConfig) -> Duration {
+    Duration::from_secs(config.lfp_ping_interval_minutes as u64 * 60)
+}
+
 #[derive(Default)]
 struct ChannelMap(HashMap<u64, Channel>);
 
@@ -100,6 +177,7 @@ impl Channel {
         self.registrations.push(Registration {
             user_id: user.get(),
             message_id: message.get(),
+            posted_at: Some(SystemTime::now()),
         });
     }
 
@@ -119,6 +197,21 @@ impl Channel {
 struct Registration {
     user_id: u64,
     message_id: u64,
+    /// Missing for registrations stored before this was recorded.
+    #[serde(default)]
+    posted_at: Option<SystemTime>,
+}
+
+impl Registration {
+    /// How much longer the message must stay up so that a ping can't be hidden by quickly
+    /// removing the reaction.
+    fn remaining_cooldown(&self, config: &DiscordConfig) -> Duration {
+        let elapsed = self
+            .posted_at
+            .and_then(|posted_at| posted_at.elapsed().ok())
+            .unwrap_or(Duration::MAX);
+        ping_interval(config).saturating_sub(elapsed)
+    }
 }
 
 pub struct Handler {
@@ -126,6 +219,14 @@ pub struct Handler {
 }
 
 impl Handler {
+    /// The configured LFP reaction, or `None` if it isn't a valid emoji.
+    fn reaction(&self) -> Option<ReactionType> {
+        match &self.config.lfp_reaction {
+            Some(reaction) => parse_reaction(reaction),
+            None => Some(DEFAULT_REACTION.into()),
+        }
+    }
+
     pub async fn setup_for_channel(
         &self,
         ctx: &SerenityContext,
@@ -136,10 +237,11 @@ impl Handler {
             .first()
             .copied()
             .ok_or(CommandError::MustMentionRole)?;
+        let reaction = self.reaction().ok_or(CommandError::InvalidLfpReaction)?;
 
         let description = self.parse_description(&message.content).unwrap_or(format!(
             "Add a {} reaction to register as *looking for players*",
-            REACTION
+            reaction
         ));
 
         let register_message = message
@@ -148,7 +250,7 @@ impl Handler {
                 &ctx.http,
                 CreateMessage::new()
                     .content(description)
-                    .reactions(vec![REACTION]),
+                    .reactions(vec![reaction]),
             )
             .await?;
 
@@ -157,9 +259,12 @@ impl Handler {
             _ => return Err(CommandError::CannotRunHere),
         };
 
-        let webhook = channel
-            .create_webhook(&ctx.http, CreateWebhook::new("Looking For Players"))
-            .await?;
+        let webhook = create_webhook_with_retry(
+            &ctx.http,
+            channel.id,
+            CreateWebhook::new("Looking For Players"),
+        )
+        .await?;
 
         let mut data = ctx.data.write().await;
         let store = data.get_mut::<StoreKey>().unwrap();
@@ -173,11 +278,69 @@ impl Handler {
         Ok(())
     }
 
+    /// Replies with everyone currently registered as looking for players in this channel.
+    pub async fn list(&self, ctx: &SerenityContext, message: &Message) -> CommandResult {
+        let channel = self
+            .get_channel(ctx, message.channel_id)
+            .await
+            .ok_or(CommandError::LfpNotSetUp)?;
+
+        let mut lines = channel
+            .registrations
+            .iter()
+            .take(MAX_LISTED_REGISTRATIONS)
+            .map(|registration| {
+                let user = UserId::new(registration.user_id).mention();
+                let registered_at = registration
+                    .posted_at
+                    .and_then(|posted_at| posted_at.duration_since(SystemTime::UNIX_EPOCH).ok());
+                match registered_at {
+                    Some(registered_at) => {
+                        format!("{} since <t:{}:R>", user, registered_at.as_secs())
+                    }
+                    None => user.to_string(),
+                }
+            })
+            .collect::<Vec<_>>();
+        if channel.registrations.len() > MAX_LISTED_REGISTRATIONS {
+            lines.push(format!(
+                "...and {} more",
+                channel.registrations.len() - MAX_LISTED_REGISTRATIONS
+            ));
+        }
+
+        let description = if lines.is_empty() {
+            "Nobody is looking for players right now.".to_owned()
+        } else {
+            lines.join("\n")
+        };
+        let embed = CreateEmbed::new()
+            .title("Looking for players")
+            .description(description);
+        message
+            .channel_id
+            .send_message(
+                ctx,
+                CreateMessage::new().embed(embed).reference_message(message),
+            )
+            .await?;
+
+        Ok(())
+    }
+
     fn parse_description(&self, message: &str) -> Option<String> {
         message.find('\n').map(|idx| message[idx..].to_owned())
     }
 
+    fn is_lfp_reaction(&self, reaction: &Reaction) -> bool {
+        self.reaction()
+            .is_some_and(|lfp_reaction| is_same_reaction(&lfp_reaction, &reaction.emoji))
+    }
+
     pub async fn handle_reaction_add(&self, ctx: &SerenityContext, reaction: Reaction) {
+        if !self.is_lfp_reaction(&reaction) {
+            return;
+        }
         if let Some(channel) = self.get_channel(ctx, reaction.channel_id).await {
             if let (Some(user), Some(guild_id)) = (reaction.user_id, reaction.guild_id) {
                 if let Err(err) = self.add_registration(ctx, user, guild_id, channel).await {
@@ -252,6 +415,9 @@ impl Handler {
     }
 
     pub async fn handle_reaction_remove(&self, ctx: &SerenityContext, reaction: Reaction) {
+        if !self.is_lfp_reaction(&reaction) {
+            return;
+        }
         if let Some(channel) = self.get_channel(ctx, reaction.channel_id).await {
             if let (Some(user), Some(guild_id)) = (reaction.user_id, reaction.guild_id) {
                 if let Err(err) = self
@@ -297,9 +463,22 @@ impl Handler {
             .await;
 
         if let Some(registration) = registration {
-            channel_id
-                .delete_message(&ctx.http, registration.message_id)
-                .await?;
+            let cooldown = registration.remaining_cooldown(&self.config);
+            if cooldown.is_zero() {
+                channel_id
+                    .delete_message(&ctx.http, registration.message_id)
+                    .await?;
+            } else {
+                let deletion = PendingDeletion {
+                    channel_id: channel_id.get(),
+                    message_id: registration.message_id,
+                    delete_at: SystemTime::now() + cooldown,
+                };
+                store
+                    .write(|store| store.pending_deletions.push(deletion))
+                    .await;
+                schedule_deletion(ctx.http.clone(), ctx.data.clone(), deletion);
+            }
         }
 
         Ok(())
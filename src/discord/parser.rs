@@ -0,0 +1,140 @@
+//! Splits bot commands into arguments, keeping quoted strings together as a single argument.
+
+enum State {
+    Whitespace,
+    String(String),
+    QuotedString { token: String, escaped: bool },
+}
+
+/// Splits a command on whitespace, treating anything between double quotes as one token. A quote
+/// only opens a quoted string at the start of a token. Within a quoted string, `\"` and `\\`
+/// stand for a literal quote and backslash; any other backslash is kept as it is.
+///
+/// Returns `None` if the input ends inside a quoted string.
+pub fn parse_command(input: &str) -> Option<Vec<String>> {
+    let mut result = Vec::new();
+    let mut state = State::Whitespace;
+
+    for c in input.chars() {
+        state = match state {
+            State::Whitespace if c.is_whitespace() => State::Whitespace,
+            State::Whitespace if c == '"' => State::QuotedString {
+                token: String::new(),
+                escaped: false,
+            },
+            State::Whitespace => State::String(c.to_string()),
+            State::String(token) if c.is_whitespace() => {
+                result.push(token);
+                State::Whitespace
+            }
+            State::String(mut token) => {
+                token.push(c);
+                State::String(token)
+            }
+            State::QuotedString {
+                mut token,
+                escaped: true,
+            } => {
+                if c != '"' && c != '\\' {
+                    token.push('\\');
+                }
+                token.push(c);
+                State::QuotedString {
+                    token,
+                    escaped: false,
+                }
+            }
+            State::QuotedString { token, .. } if c == '\\' => State::QuotedString {
+                token,
+                escaped: true,
+            },
+            State::QuotedString { token, .. } if c == '"' => {
+                result.push(token);
+                State::Whitespace
+            }
+            State::QuotedString { mut token, .. } => {
+                token.push(c);
+                State::QuotedString {
+                    token,
+                    escaped: false,
+                }
+            }
+        };
+    }
+
+    match state {
+        State::Whitespace => {}
+        State::String(token) => result.push(token),
+        State::QuotedString { .. } => return None,
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(tokens: &[&str]) -> Option<Vec<String>> {
+        Some(tokens.iter().map(|token| token.to_string()).collect())
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            parse_command("ping  add\tbedwars "),
+            tokens(&["ping", "add", "bedwars"])
+        );
+    }
+
+    #[test]
+    fn keeps_quoted_arguments_together() {
+        assert_eq!(
+            parse_command(r#"ping changelog "Fixed beds not breaking" now"#),
+            tokens(&["ping", "changelog", "Fixed beds not breaking", "now"])
+        );
+    }
+
+    #[test]
+    fn keeps_empty_quoted_arguments() {
+        assert_eq!(
+            parse_command(r#"say "" done"#),
+            tokens(&["say", "", "done"])
+        );
+    }
+
+    #[test]
+    fn unescapes_quotes_and_backslashes() {
+        assert_eq!(
+            parse_command(r#"say "a\"b" "c\\d""#),
+            tokens(&["say", r#"a"b"#, r#"c\d"#])
+        );
+    }
+
+    #[test]
+    fn keeps_other_backslashes() {
+        assert_eq!(parse_command(r#""a\nb""#), tokens(&[r#"a\nb"#]));
+    }
+
+    #[test]
+    fn rejects_a_trailing_unterminated_quote() {
+        assert_eq!(parse_command(r#"say "unfinished"#), None);
+        assert_eq!(parse_command(r#"say "escaped end\""#), None);
+    }
+
+    #[test]
+    fn flushes_a_trailing_unquoted_token() {
+        assert_eq!(parse_command("ping list"), tokens(&["ping", "list"]));
+        assert_eq!(
+            parse_command(r#""quoted" last"#),
+            tokens(&["quoted", "last"])
+        );
+    }
+
+    #[test]
+    fn fails_when_input_ends_inside_a_quote() {
+        assert_eq!(parse_command(r#"""#), None);
+        assert_eq!(parse_command(r#"ping "a b"#), None);
+        assert_eq!(parse_command(r#"ping "a b" ""#), None);
+    }
+}
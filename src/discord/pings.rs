@@ -81,24 +81,52 @@ pub async fn send(discord: &mut DiscordClient, send_ping: SendPing) {
 
                 let new_ping = ping.try_new_ping(&discord.config);
 
-                let result = ping
+                let execute = {
+                    let mut execute = ExecuteWebhook::new()
+                        .allowed_mentions(CreateAllowedMentions::new().roles([role]))
+                        .username(send_ping.sender_name)
+                        .content(if new_ping {
+                            format!("{}! {}", role.mention(), send_ping.content)
+                        } else {
+                            send_ping.content
+                        });
+                    let icon = send_ping
+                        .sender_icon
+                        .or_else(|| discord.config.ping_default_avatar_url.clone());
+                    if let Some(icon) = icon {
+                        execute = execute.avatar_url(icon);
+                    }
+                    execute
+                };
+
+                let mut result = ping
                     .webhook
-                    .execute(&cache_and_http.http, false, {
-                        let mut execute = ExecuteWebhook::new()
-                            .allowed_mentions(CreateAllowedMentions::new().roles([role]))
-                            .username(send_ping.sender_name)
-                            .content(if new_ping {
-                                format!("{}! {}", role.mention(), send_ping.content)
-                            } else {
-                                send_ping.content
-                            });
-                        if let Some(icon) = send_ping.sender_icon {
-                            execute = execute.avatar_url(icon);
-                        }
-                        execute
-                    })
+                    .execute(&cache_and_http.http, false, execute.clone())
                     .await;
 
+                // The webhook may have been deleted by hand: replace it so that pings keep working
+                if matches!(&result, Err(err) if is_unknown_webhook_error(err)) {
+                    let channel = ChannelId::new(ping.discord_channel);
+                    let builder = CreateWebhook::new(format!("Ping {}", send_ping.ping));
+                    match create_webhook_with_retry(&cache_and_http.http, channel, builder).await {
+                        Ok(webhook) => {
+                            info!(
+                                "recreated deleted webhook for ping '{}' in {}",
+                                send_ping.ping, channel
+                            );
+                            ping.webhook = webhook;
+                            result = ping
+                                .webhook
+                                .execute(&cache_and_http.http, false, execute)
+                                .await;
+                        }
+                        Err(err) => error!(
+                            "failed to recreate webhook for ping '{}': {:?}",
+                            send_ping.ping, err
+                        ),
+                    }
+                }
+
                 if let Err(error) = result {
                     error!("failed to send ping: {:?}", error)
                 }
@@ -139,9 +167,12 @@ impl Handler {
         }
 
         if let Ok(Channel::Guild(channel)) = message.channel(ctx).await {
-            let webhook = channel
-                .create_webhook(&ctx.http, CreateWebhook::new(format!("Ping {}", ping)))
-                .await?;
+            let webhook = create_webhook_with_retry(
+                &ctx.http,
+                channel.id,
+                CreateWebhook::new(format!("Ping {}", ping)),
+            )
+            .await?;
 
             ping_store
                 .write(|ping_store| {
@@ -1,15 +1,18 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
 use tracing::error;
 use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serenity::all::{CreateAllowedMentions, CreateMessage, CreateWebhook, EditChannel};
+use serenity::all::{CreateAllowedMentions, CreateMessage, CreateWebhook, EditChannel, StatusCode};
 use serenity::client::Context as SerenityContext;
 use serenity::model::channel::{Channel, Message as SerenityMessage};
-use serenity::model::id::ChannelId;
+use serenity::model::id::{ChannelId, WebhookId};
 use serenity::model::webhook::Webhook;
 use serenity::prelude::*;
+use tokio::sync::mpsc;
 use xtra::prelude::*;
 
 use crate::controller::*;
@@ -17,6 +20,8 @@ use crate::Persistent;
 
 use super::*;
 
+const RELAY_WEBHOOK_PREFIX: &str = "Relay (";
+
 pub struct StoreKey;
 
 impl TypeMapKey for StoreKey {
@@ -36,6 +41,17 @@ impl Store {
         self.channel_to_relay.insert(channel, relay);
     }
 
+    /// Checks whether a message with the given webhook id was sent by one of our own relays.
+    pub fn is_relay_webhook(&self, webhook_id: Option<WebhookId>) -> bool {
+        match webhook_id {
+            Some(webhook_id) => self
+                .channel_to_relay
+                .values()
+                .any(|relay| relay.webhook.id == webhook_id),
+            None => false,
+        }
+    }
+
     pub fn remove_relay(&mut self, discord: u64) -> Option<(String, ChannelRelay)> {
         match self.discord_to_channel.remove(&discord) {
             Some(channel) => {
@@ -75,55 +91,238 @@ pub struct ChannelRelay {
 }
 
 pub async fn send_chat(discord: &mut DiscordClient, send_chat: SendChat) {
+    let SendChat {
+        channel,
+        sender,
+        username,
+        content,
+    } = send_chat;
+
     if let (Some(cache_and_http), Some(data)) = (&discord.cache_and_http, &discord.data) {
-        let data = data.read().await;
-        let relay_store = data.get::<StoreKey>().unwrap();
-        if let Some(relay) = relay_store.channel_to_relay.get(&send_chat.channel) {
+        let has_relay = {
+            let data = data.read().await;
+            let relay_store = data.get::<StoreKey>().unwrap();
+            relay_store.channel_to_relay.contains_key(&channel)
+        };
+
+        if has_relay {
             let avatar_url = &discord.config.player_avatar_url;
 
-            let result = relay
-                .webhook
-                .execute(&cache_and_http.http, false, {
-                    let mut execute = ExecuteWebhook::new()
-                        .username(send_chat.sender.name)
-                        .content(send_chat.content)
-                        .allowed_mentions(CreateAllowedMentions::new());
-
-                    if let Some(avatar_url) = avatar_url {
-                        let id = send_chat.sender.id.replace('-', "");
-                        let avatar_url = format!("{}/{}", avatar_url, id);
-                        execute = execute.avatar_url(avatar_url);
+            let execute = {
+                let mut execute = ExecuteWebhook::new()
+                    .username(username)
+                    .allowed_mentions(CreateAllowedMentions::new());
+
+                // Webhook usernames can't be colored, so colored names are shown with an embed
+                execute = match sender.name_color {
+                    Some(color) => {
+                        execute.embed(CreateEmbed::new().description(content).color(color))
                     }
+                    None => execute.content(content),
+                };
 
-                    execute
-                })
-                .await;
+                if let Some(avatar_url) = avatar_url {
+                    let id = sender.id.replace('-', "");
+                    let avatar_url = format!("{}/{}", avatar_url, id);
+                    execute = execute.avatar_url(avatar_url);
+                }
 
-            if let Err(error) = result {
-                warn!("failed to relay chat message over webhook: {:?}", error);
+                execute
+            };
+
+            let queue = relay_queue(&mut discord.relay_queues, &channel, || {
+                spawn_relay_queue(cache_and_http.http.clone(), data.clone(), channel.clone())
+            });
+            let _ = queue.send(RelayMessage::Chat(execute));
+        }
+    }
+}
+
+/// A message waiting in a relay's queue to be sent to its Discord channel.
+pub enum RelayMessage {
+    /// Chat from a player, sent over the relay's webhook.
+    Chat(ExecuteWebhook),
+    /// A message from the server itself, sent by the bot.
+    System(CreateMessage),
+}
+
+/// Gets the queue of messages to be sent to the given relay, spawning it if there isn't one yet or
+/// if its task has stopped.
+///
+/// Each relay has its own queue so that messages are delivered in the order they were received,
+/// without a slow channel holding up the others.
+fn relay_queue<'a, T>(
+    queues: &'a mut HashMap<String, mpsc::UnboundedSender<T>>,
+    channel: &str,
+    mut spawn: impl FnMut() -> mpsc::UnboundedSender<T>,
+) -> &'a mpsc::UnboundedSender<T> {
+    let queue = queues.entry(channel.to_owned()).or_insert_with(&mut spawn);
+    if queue.is_closed() {
+        *queue = spawn();
+    }
+    queue
+}
+
+/// Spawns a task which sends every message sent to it to the relay's channel, one at a time.
+fn spawn_relay_queue(
+    http: Arc<Http>,
+    data: Arc<RwLock<TypeMap>>,
+    channel: String,
+) -> mpsc::UnboundedSender<RelayMessage> {
+    spawn_send_queue(move |message| {
+        let (http, data, channel) = (http.clone(), data.clone(), channel.clone());
+        async move {
+            match message {
+                RelayMessage::Chat(execute) => {
+                    execute_relay_webhook(&http, &data, &channel, execute).await
+                }
+                RelayMessage::System(message) => {
+                    send_relay_message(&http, &data, &channel, message).await
+                }
             }
         }
+    })
+}
+
+/// Spawns a task which passes every message sent to it to `send`, waiting for each to be sent
+/// before starting on the next so that they are delivered in order.
+fn spawn_send_queue<T, F, Fut>(mut send: F) -> mpsc::UnboundedSender<T>
+where
+    T: Send + 'static,
+    F: FnMut(T) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel::<T>();
+
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            send(message).await;
+        }
+    });
+
+    sender
+}
+
+async fn execute_relay_webhook(
+    http: &Http,
+    data: &RwLock<TypeMap>,
+    channel: &str,
+    execute: ExecuteWebhook,
+) {
+    // The webhook is looked up for every message, as it may have been replaced since
+    let webhook = {
+        let data = data.read().await;
+        let relay_store = data.get::<StoreKey>().unwrap();
+        relay_store
+            .channel_to_relay
+            .get(channel)
+            .map(|relay| relay.webhook.clone())
+    };
+    let Some(webhook) = webhook else {
+        return;
+    };
+
+    let mut result = webhook.execute(http, false, execute.clone()).await;
+
+    // The webhook may have been deleted by hand: replace it so that the relay keeps working
+    if matches!(&result, Err(err) if is_unknown_webhook_error(err)) {
+        if let Some(webhook) = recreate_webhook(http, data, channel).await {
+            result = webhook.execute(http, false, execute).await;
+        }
+    }
+
+    if let Err(error) = result {
+        warn!("failed to relay chat message over webhook: {:?}", error);
+    }
+}
+
+/// Creates a new webhook for a relay whose webhook has been deleted, updating the store.
+async fn recreate_webhook(http: &Http, data: &RwLock<TypeMap>, channel: &str) -> Option<Webhook> {
+    let mut data = data.write().await;
+    let relay_store = data.get_mut::<StoreKey>().unwrap();
+    let discord_channel =
+        ChannelId::new(relay_store.channel_to_relay.get(channel)?.discord_channel);
+
+    let builder = CreateWebhook::new(format!("{}{})", RELAY_WEBHOOK_PREFIX, channel));
+    match create_webhook_with_retry(http, discord_channel, builder).await {
+        Ok(webhook) => {
+            info!(
+                "recreated deleted relay webhook for '{}' in {}",
+                channel, discord_channel
+            );
+            relay_store
+                .write(|relay_store| {
+                    let relay = relay_store.channel_to_relay.get_mut(channel)?;
+                    relay.webhook = webhook.clone();
+                    Some(webhook)
+                })
+                .await
+        }
+        Err(err) => {
+            error!(
+                "failed to recreate relay webhook for '{}': {:?}",
+                channel, err
+            );
+            None
+        }
     }
 }
 
 pub async fn send_system(discord: &mut DiscordClient, send_system: SendSystem) {
+    let SendSystem { channel, content } = send_system;
+
     if let (Some(cache_and_http), Some(data)) = (&discord.cache_and_http, &discord.data) {
+        let has_relay = {
+            let data = data.read().await;
+            let relay_store = data.get::<StoreKey>().unwrap();
+            relay_store.channel_to_relay.contains_key(&channel)
+        };
+
+        if has_relay {
+            let message = CreateMessage::new()
+                .content(content)
+                .allowed_mentions(CreateAllowedMentions::new());
+
+            // System messages share the chat queue so they can't overtake chat sent before them
+            let queue = relay_queue(&mut discord.relay_queues, &channel, || {
+                spawn_relay_queue(cache_and_http.http.clone(), data.clone(), channel.clone())
+            });
+            let _ = queue.send(RelayMessage::System(message));
+        }
+    }
+}
+
+async fn send_relay_message(
+    http: &Http,
+    data: &RwLock<TypeMap>,
+    channel: &str,
+    message: CreateMessage,
+) {
+    let discord_channel = {
         let data = data.read().await;
         let relay_store = data.get::<StoreKey>().unwrap();
-        if let Some(relay) = relay_store.channel_to_relay.get(&send_system.channel) {
-            let result = ChannelId::new(relay.discord_channel)
-                .send_message(
-                    &cache_and_http.http,
-                    CreateMessage::new()
-                        .content(send_system.content)
-                        .allowed_mentions(CreateAllowedMentions::new()),
-                )
-                .await;
+        relay_store
+            .channel_to_relay
+            .get(channel)
+            .map(|relay| ChannelId::new(relay.discord_channel))
+    };
+    let Some(discord_channel) = discord_channel else {
+        return;
+    };
 
-            if let Err(error) = result {
-                warn!("failed to send system message: {:?}", error);
-            }
+    if let Err(error) = discord_channel.send_message(http, message).await {
+        warn!("failed to send system message: {:?}", error);
+    }
+}
+
+pub async fn is_attached(discord: &DiscordClient, channel: &str) -> bool {
+    match &discord.data {
+        Some(data) => {
+            let data = data.read().await;
+            let relay_store = data.get::<StoreKey>().unwrap();
+            relay_store.channel_to_relay.contains_key(channel)
         }
+        None => false,
     }
 }
 
@@ -179,12 +378,12 @@ impl Handler {
 
         match message.channel(ctx).await {
             Ok(Channel::Guild(guild_channel)) => {
-                let webhook = guild_channel
-                    .create_webhook(
-                        &ctx.http,
-                        CreateWebhook::new(format!("Relay ({})", channel)),
-                    )
-                    .await?;
+                let webhook = create_webhook_with_retry(
+                    &ctx.http,
+                    guild_channel.id,
+                    CreateWebhook::new(format!("{}{})", RELAY_WEBHOOK_PREFIX, channel)),
+                )
+                .await?;
 
                 let relay = ChannelRelay {
                     discord_channel: message.channel_id.get(),
@@ -213,16 +412,79 @@ impl Handler {
 
         let relay_store = data.get_mut::<StoreKey>().unwrap();
 
-        let (_, relay) = relay_store
-            .write(
-                |relay_store| match relay_store.remove_relay(message.channel_id.get()) {
-                    Some(channel) => Ok(channel),
-                    None => Err(CommandError::ChannelNotConnected),
-                },
-            )
-            .await?;
+        let relay = relay_store
+            .discord_to_channel
+            .get(&message.channel_id.get())
+            .and_then(|channel| relay_store.channel_to_relay.get(channel))
+            .ok_or(CommandError::ChannelNotConnected)?;
+
+        // Delete the webhook before forgetting about it so that a failure doesn't leave it
+        // orphaned in Discord. A webhook that is already gone is fine to forget.
+        match relay.webhook.delete(&ctx.http).await {
+            Err(serenity::Error::Http(err)) if err.status_code() == Some(StatusCode::NOT_FOUND) => {
+                warn!("relay webhook {} was already deleted", relay.webhook.id);
+            }
+            Err(err) => {
+                error!(
+                    "failed to delete relay webhook {} in channel {}, keeping the relay: {}",
+                    relay.webhook.id, message.channel_id, err
+                );
+                return Err(err.into());
+            }
+            Ok(()) => {}
+        }
 
-        relay.webhook.delete(&ctx.http).await?;
+        relay_store
+            .write(|relay_store| relay_store.remove_relay(message.channel_id.get()))
+            .await;
+
+        Ok(())
+    }
+
+    /// Deletes relay webhooks in this guild that no longer belong to any stored relay.
+    pub async fn cleanup(&self, ctx: &SerenityContext, message: &SerenityMessage) -> CommandResult {
+        let guild_id = message.guild_id.ok_or(CommandError::CannotRunHere)?;
+        let bot_id = ctx.cache.current_user().id;
+
+        let data = ctx.data.read().await;
+        let relay_store = data.get::<StoreKey>().unwrap();
+
+        let orphans = guild_id
+            .webhooks(&ctx.http)
+            .await?
+            .into_iter()
+            .filter(|webhook| {
+                let ours = webhook.user.as_ref().map(|user| user.id) == Some(bot_id);
+                let relay = webhook
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| name.starts_with(RELAY_WEBHOOK_PREFIX));
+                ours && relay && !relay_store.is_relay_webhook(Some(webhook.id))
+            })
+            .collect::<Vec<_>>();
+
+        let mut deleted = Vec::with_capacity(orphans.len());
+        for webhook in orphans {
+            let name = webhook.name.clone().unwrap_or_default();
+            match webhook.delete(&ctx.http).await {
+                Ok(()) => deleted.push(format!("{} ({})", name, webhook.id)),
+                Err(err) => error!(
+                    "failed to delete orphaned relay webhook {}: {}",
+                    webhook.id, err
+                ),
+            }
+        }
+
+        let reply = if deleted.is_empty() {
+            "No orphaned relay webhooks found.".to_owned()
+        } else {
+            format!(
+                "Deleted {} orphaned relay webhooks:\n{}",
+                deleted.len(),
+                deleted.join("\n")
+            )
+        };
+        message.reply(ctx, reply).await?;
 
         Ok(())
     }
@@ -235,6 +497,14 @@ impl Handler {
             .discord_to_channel
             .get(&message.channel_id.get())
         {
+            if relay_store.is_relay_webhook(message.webhook_id) {
+                warn!(
+                    "ignoring message sent by a relay webhook in channel {} to avoid an echo loop",
+                    channel
+                );
+                return;
+            }
+
             let message = self.parse_outgoing_chat_with_reply(ctx, message).await;
 
             self.controller
@@ -280,6 +550,36 @@ impl Handler {
         }
     }
 
+    pub async fn broadcast_command(
+        &self,
+        ctx: &SerenityContext,
+        message: &SerenityMessage,
+        command: &[&str],
+    ) -> CommandResult {
+        let sender = self.sender_name(ctx, message).await;
+        let roles = if let Ok(member) = message.member(&ctx).await {
+            member.roles.iter().map(ToString::to_string).collect()
+        } else {
+            Vec::new()
+        };
+
+        let received = self
+            .controller
+            .send(BroadcastCommand {
+                command: command.join(" "),
+                sender,
+                roles,
+            })
+            .await
+            .expect("controller disconnected");
+
+        message
+            .reply(ctx, format!("Sent command to {} servers.", received))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn send_outgoing_command(&self, ctx: &SerenityContext, message: &SerenityMessage) {
         let data = ctx.data.read().await;
 
@@ -345,6 +645,10 @@ impl Handler {
             .map(|attachment| ChatAttachment {
                 name: attachment.filename.clone(),
                 url: attachment.url.clone(),
+                content_type: attachment.content_type.clone(),
+                size: Some(attachment.size),
+                width: attachment.width,
+                height: attachment.height,
             })
             .collect();
 
@@ -408,3 +712,84 @@ impl Handler {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn chat_queue_keeps_bursts_in_order() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let queue = spawn_send_queue(move |message: u64| {
+            let sent = sent.clone();
+            async move {
+                // Earlier messages take longer, so they would be overtaken if sent concurrently
+                tokio::time::sleep(Duration::from_millis(20 - message)).await;
+                let _ = sent.send(message);
+            }
+        });
+
+        for message in 0..20 {
+            queue.send(message).unwrap();
+        }
+        drop(queue);
+
+        let mut order = Vec::new();
+        while let Some(message) = received.recv().await {
+            order.push(message);
+        }
+        assert_eq!(order, (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn later_bursts_reuse_the_relay_queue() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let mut spawned = 0;
+        let mut queues = HashMap::new();
+        let mut spawn = || {
+            spawned += 1;
+            let sent = sent.clone();
+            spawn_send_queue(move |message: u64| {
+                let sent = sent.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(20 - message)).await;
+                    let _ = sent.send(message);
+                }
+            })
+        };
+
+        for message in 0..10 {
+            relay_queue(&mut queues, "lobby", &mut spawn)
+                .send(message)
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        for message in 10..20 {
+            relay_queue(&mut queues, "lobby", &mut spawn)
+                .send(message)
+                .unwrap();
+        }
+        drop(sent);
+        assert_eq!(spawned, 1);
+        queues.clear();
+
+        let mut order = Vec::new();
+        while let Some(message) = received.recv().await {
+            order.push(message);
+        }
+        assert_eq!(order, (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn closed_relay_queues_are_respawned() {
+        let mut queues = HashMap::new();
+        let (closed, _) = mpsc::unbounded_channel::<u64>();
+        queues.insert("lobby".to_owned(), closed);
+
+        let queue = relay_queue(&mut queues, "lobby", || spawn_send_queue(|_| async {}));
+        assert!(!queue.is_closed());
+        assert!(queue.send(1).is_ok());
+    }
+}
@@ -1,52 +1,154 @@
+use std::convert::Infallible;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use bytes::Bytes;
-use futures::{Sink, SinkExt, Stream, StreamExt};
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
 use tracing::{debug, error, info, warn};
-use serde::{Deserialize, Serialize};
 
+use socket2::{SockRef, TcpKeepalive};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+use warp::ws::{Message as WsMessage, WebSocket, Ws};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
 use xtra::prelude::*;
 
 use crate::controller::*;
 use crate::model::*;
 use crate::statistics::database::UploadStatsBundle;
-use crate::statistics::model::GameStatsBundle;
 use crate::IntegrationsConfig;
-use uuid::Uuid;
 
-const MAX_FRAME_LENGTH: usize = 4 * 1024 * 1024;
-const FRAME_HEADER_SIZE: usize = 4;
+pub mod protocol;
+
+pub use protocol::{IncomingMessage, OutgoingMessage};
 
 pub async fn run(controller: Address<Controller>, config: IntegrationsConfig) {
     let listener = TcpListener::bind(&format!("0.0.0.0:{}", config.port))
         .await
         .expect("failed to open integrations listener");
 
+    let connection_limit = config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+
+    if let Some(port) = config.websocket_port {
+        tokio::spawn(run_websocket(
+            controller.clone(),
+            port,
+            connection_limit.clone(),
+        ));
+    }
+
+    let keepalive_interval = Duration::from_secs(config.keepalive_interval_seconds);
+
     loop {
         let (stream, addr) = listener
             .accept()
             .await
             .expect("failed to accept integrations connection");
 
-        info!("accepting integrations connection from {:?}", addr);
+        if let Err(err) = configure_socket(&stream, keepalive_interval) {
+            warn!(
+                "failed to configure integrations socket for {:?}: {}",
+                addr, err
+            );
+        }
+
+        let Ok(permit) = acquire_connection(&connection_limit, Some(addr)) else {
+            continue;
+        };
 
         let controller = controller.clone();
         tokio::spawn(async move {
-            match run_client(controller, stream).await {
+            let (sink, stream) = split_framed(stream);
+            match run_client(controller, sink, stream).await {
                 Ok(_) => error!("integrations client disconnected"),
                 Err(e) => error!("client exited with error: {:?}", e),
             }
+            drop(permit);
         });
     }
 }
 
+/// Disables Nagle's algorithm, as chat and commands are small and latency-sensitive, and enables
+/// keepalive so that dead peers are detected.
+fn configure_socket(stream: &TcpStream, keepalive_interval: Duration) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let keepalive = TcpKeepalive::new()
+        .with_time(keepalive_interval)
+        .with_interval(keepalive_interval);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Accepts integrations clients over WebSocket on `/integrations`, for environments where raw
+/// TCP connections are hard to get through. Each text frame carries one JSON message, exactly as
+/// a frame would over TCP.
+async fn run_websocket(
+    controller: Address<Controller>,
+    port: u16,
+    connection_limit: Option<Arc<Semaphore>>,
+) {
+    let route = warp::path("integrations")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::addr::remote())
+        .and_then(move |ws: Ws, addr: Option<SocketAddr>| {
+            let permit = acquire_connection(&connection_limit, addr);
+            let controller = controller.clone();
+            async move {
+                let Ok(permit) = permit else {
+                    let status = StatusCode::SERVICE_UNAVAILABLE;
+                    return Ok::<_, Infallible>(Box::new(status) as Box<dyn Reply>);
+                };
+
+                Ok(Box::new(ws.on_upgrade(move |socket| async move {
+                    let (sink, stream) = split_websocket(socket);
+                    match run_client(controller, sink, stream).await {
+                        Ok(_) => error!("integrations client disconnected"),
+                        Err(e) => error!("client exited with error: {:?}", e),
+                    }
+                    drop(permit);
+                })))
+            }
+        });
+
+    warp::serve(route).run(([0, 0, 0, 0], port)).await;
+}
+
+/// Takes a connection permit, which must be held for as long as the client is connected.
+fn acquire_connection(
+    connection_limit: &Option<Arc<Semaphore>>,
+    addr: Option<SocketAddr>,
+) -> std::result::Result<Option<OwnedSemaphorePermit>, ()> {
+    let permit = match connection_limit {
+        Some(limit) => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                warn!(
+                    "rejecting integrations connection from {:?}: too many connections",
+                    addr
+                );
+                return Err(());
+            }
+        },
+        None => None,
+    };
+
+    info!("accepting integrations connection from {:?}", addr);
+    Ok(permit)
+}
+
 struct Handshake {
     channel: String,
     game_version: String,
     server_ip: Option<String>,
     server_type: ServerType,
+    protocol_version: Option<u32>,
 }
 
 async fn handshake<S: Stream<Item = HandleIncomingMessage> + Unpin>(
@@ -59,11 +161,13 @@ async fn handshake<S: Stream<Item = HandleIncomingMessage> + Unpin>(
                 game_version,
                 server_ip,
                 server_type,
+                protocol_version,
             }) => Ok(Handshake {
                 channel,
                 game_version,
                 server_ip,
                 server_type: server_type.unwrap_or(ServerType::Minecraft),
+                protocol_version,
             }),
             Ok(_) => Err(Error::MissingHandshake),
             Err(err) => Err(err),
@@ -72,14 +176,18 @@ async fn handshake<S: Stream<Item = HandleIncomingMessage> + Unpin>(
     }
 }
 
-async fn run_client(controller: Address<Controller>, stream: TcpStream) -> Result<()> {
-    let (sink, mut stream) = split_framed(stream);
+async fn run_client<Tx, Rx>(controller: Address<Controller>, sink: Tx, mut stream: Rx) -> Result<()>
+where
+    Tx: Sink<OutgoingMessage, Error = Error> + Send + Sync + 'static,
+    Rx: Stream<Item = HandleIncomingMessage> + Unpin,
+{
     let handshake = handshake(&mut stream).await?;
-    let (channel, game_version, server_ip, server_type) = (
+    let (channel, game_version, server_ip, server_type, protocol_version) = (
         handshake.channel,
         handshake.game_version,
         handshake.server_ip,
         handshake.server_type,
+        handshake.protocol_version,
     );
 
     info!(
@@ -91,21 +199,31 @@ async fn run_client(controller: Address<Controller>, stream: TcpStream) -> Resul
         controller: controller.clone(),
         channel: channel.clone(),
         sink: Box::pin(sink),
-        server_type,
+        server_type: server_type.clone(),
     };
 
-    let client = xtra::spawn_tokio(client, Mailbox::unbounded());
+    // Register before spawning so that a rejected client never starts, and so never
+    // unregisters the client that is already connected on this channel.
+    let (address, mailbox) = Mailbox::unbounded();
 
-    controller
+    let registered = controller
         .send(RegisterIntegrationsClient {
-            channel,
+            channel: channel.clone(),
             game_version,
             server_ip,
-            client: client.clone(),
+            server_type,
+            protocol_version,
+            client: address.clone(),
         })
         .await
         .expect("controller disconnected");
 
+    if !registered {
+        return Err(Error::DuplicateChannel(channel));
+    }
+
+    let client = xtra::spawn_tokio(client, (address, mailbox));
+
     if let Err(e) = stream.map(Ok).forward(client.into_sink()).await {
         error!("error in integrations client: {e}");
     }
@@ -131,65 +249,6 @@ impl Actor for IntegrationsClient {
     }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type", content = "body")]
-pub enum IncomingMessage {
-    #[serde(rename = "handshake")]
-    Handshake {
-        channel: String,
-        game_version: String,
-        server_ip: Option<String>,
-        server_type: Option<ServerType>,
-    },
-    #[serde(rename = "chat")]
-    Chat { sender: Player, content: String },
-    #[serde(rename = "status")]
-    Status {
-        #[serde(default)]
-        players: Option<Vec<Player>>,
-        #[serde(default)]
-        games: Option<Vec<Game>>,
-    },
-    #[serde(rename = "lifecycle_start")]
-    LifecycleStart {},
-    #[serde(rename = "lifecycle_stop")]
-    LifecycleStop { crash: bool },
-    #[serde(rename = "performance")]
-    Performance(ServerPerformance),
-    #[serde(rename = "system")]
-    SystemMessage { content: String },
-    #[serde(rename = "upload_statistics")]
-    UploadStatistics {
-        bundle: GameStatsBundle,
-        game_id: Uuid,
-    },
-}
-
-#[derive(Serialize, Debug)]
-#[serde(tag = "type", content = "body")]
-pub enum OutgoingMessage {
-    #[serde(rename = "chat")]
-    Chat(ChatMessage),
-    #[serde(rename = "command")]
-    Command {
-        command: String,
-        sender: String,
-        roles: Vec<String>,
-        silent: bool,
-    },
-    #[serde(rename = "send_to_server")]
-    SendToServer {
-        // The UUID
-        player: String,
-        target_server: String,
-    },
-    #[serde(rename = "send_server_to_server")]
-    SendServerToServer {
-        from_server: String,
-        to_server: String,
-    },
-}
-
 struct HandleIncomingMessage(Result<IncomingMessage>);
 
 impl Handler<HandleIncomingMessage> for IntegrationsClient {
@@ -216,6 +275,21 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
                         };
                         self.controller.send(status_update).await
                     }
+                    StatusDelta {
+                        add_players,
+                        remove_players,
+                        add_games,
+                        remove_games,
+                    } => {
+                        let status_delta = crate::controller::StatusDelta {
+                            channel: self.channel.clone(),
+                            add_games,
+                            remove_games,
+                            add_players,
+                            remove_players,
+                        };
+                        self.controller.send(status_delta).await
+                    }
                     LifecycleStart {} => {
                         let lifecycle = ServerLifecycleStart {
                             channel: self.channel.clone(),
@@ -245,7 +319,14 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
                         };
                         self.controller.send(system_message).await
                     }
-                    UploadStatistics { bundle, game_id } => {
+                    UploadStatistics {
+                        bundle,
+                        game_id,
+                        dry_run,
+                    } => {
+                        let requested_id = game_id.filter(|id| !id.is_nil());
+                        let game_id = requested_id.unwrap_or_else(Uuid::new_v4);
+
                         if let Some(global) = &bundle.stats.global {
                             debug!("server '{}' uploaded {} player statistics and {} global statistics in statistics bundle for {}",
                                 self.channel, bundle.stats.players.len(), global.len(), bundle.namespace);
@@ -257,8 +338,52 @@ impl Handler<HandleIncomingMessage> for IntegrationsClient {
                             game_id,
                             bundle,
                             server: self.channel.clone(),
+                            dry_run,
+                        };
+                        match self.controller.send(upload_bundle_message).await {
+                            Ok(Ok(Some(bundle))) => {
+                                let message = OutgoingMessage::StatisticsDryRun { game_id, bundle };
+                                let _ = self.sink.send(message).await;
+                                Ok(())
+                            }
+                            Ok(Ok(None)) => {
+                                if requested_id.is_none() {
+                                    let message = OutgoingMessage::StatisticsUploaded { game_id };
+                                    let _ = self.sink.send(message).await;
+                                }
+                                Ok(())
+                            }
+                            Ok(Err(rejected)) => {
+                                let message = OutgoingMessage::StatisticsRejected {
+                                    game_id,
+                                    reason: rejected.to_string(),
+                                };
+                                let _ = self.sink.send(message).await;
+                                Ok(())
+                            }
+                            Err(err) => Err(err),
+                        }
+                    }
+                    UpdateServerInfo {
+                        server_ip,
+                        game_version,
+                    } => {
+                        let update = ServerInfoUpdate {
+                            channel: self.channel.clone(),
+                            server_ip,
+                            game_version,
                         };
-                        self.controller.send(upload_bundle_message).await
+                        self.controller.send(update).await
+                    }
+                    QueryConfig {} => {
+                        let query = GetChannelConfig(self.channel.clone());
+                        match self.controller.send(query).await {
+                            Ok(config) => {
+                                let _ = self.sink.send(OutgoingMessage::Config(config)).await;
+                                Ok(())
+                            }
+                            Err(err) => Err(err),
+                        }
                     }
                     _ => {
                         warn!(
@@ -299,28 +424,35 @@ fn split_framed(
     impl Sink<OutgoingMessage, Error = Error> + Send,
     impl Stream<Item = HandleIncomingMessage>,
 ) {
-    let (sink, stream) = tokio_util::codec::LengthDelimitedCodec::builder()
-        .big_endian()
-        .max_frame_length(MAX_FRAME_LENGTH)
-        .length_field_length(FRAME_HEADER_SIZE)
-        .num_skip(FRAME_HEADER_SIZE)
-        .length_field_offset(0)
-        .length_adjustment(0)
-        .new_framed(stream)
-        .split();
-
-    let sink = sink.with(|message: OutgoingMessage| async move {
-        let mut bytes = Vec::with_capacity(64);
-        serde_json::to_writer(&mut bytes, &message)?;
-        Ok(Bytes::from(bytes))
-    });
-
-    let stream = stream.map(|result| {
-        HandleIncomingMessage(match result {
-            Ok(bytes) => serde_json::from_slice(bytes.as_ref()).map_err(Error::Json),
-            Err(err) => Err(err.into()),
-        })
-    });
+    let (sink, stream) = protocol::split_framed(stream);
+    (sink, stream.map(HandleIncomingMessage))
+}
+
+fn split_websocket(
+    socket: WebSocket,
+) -> (
+    impl Sink<OutgoingMessage, Error = Error> + Send,
+    impl Stream<Item = HandleIncomingMessage> + Unpin,
+) {
+    let (sink, stream) = socket.split();
+
+    let sink = sink
+        .sink_map_err(Error::from)
+        .with(|message: OutgoingMessage| async move {
+            Ok::<_, Error>(WsMessage::text(serde_json::to_string(&message)?))
+        });
+
+    // Control frames are handled by the WebSocket itself, and we never expect binary frames
+    let stream = stream
+        .take_while(|result| future::ready(!matches!(result, Ok(message) if message.is_close())))
+        .filter_map(|result| {
+            future::ready(match result {
+                Ok(message) => message.to_str().ok().map(|text| {
+                    HandleIncomingMessage(serde_json::from_str(text).map_err(Error::from))
+                }),
+                Err(err) => Some(HandleIncomingMessage(Err(err.into()))),
+            })
+        });
 
     (sink, stream)
 }
@@ -333,6 +465,10 @@ enum Error {
     Io(#[from] io::Error),
     #[error("invalid json")]
     Json(#[from] serde_json::Error),
+    #[error("websocket error")]
+    WebSocket(#[from] warp::Error),
     #[error("missing handshake")]
     MissingHandshake,
+    #[error("channel '{0}' already has a connected client")]
+    DuplicateChannel(String),
 }
@@ -0,0 +1,171 @@
+//! The wire protocol spoken between the backend and integrations clients.
+//!
+//! Every message is a JSON object tagged by `type`, sent in a frame prefixed with its length as a
+//! big-endian `u32`. Nothing here depends on the server actors, so clients can share these types.
+
+use std::io;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::LengthDelimitedCodec;
+use uuid::Uuid;
+
+use crate::model::*;
+use crate::statistics::model::GameStatsBundle;
+
+pub const MAX_FRAME_LENGTH: usize = 4 * 1024 * 1024;
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// A message sent from an integrations client to the backend.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "body")]
+pub enum IncomingMessage {
+    #[serde(rename = "handshake")]
+    Handshake {
+        channel: String,
+        game_version: String,
+        server_ip: Option<String>,
+        server_type: Option<ServerType>,
+        /// The protocol version spoken by the client, if it reports one.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+    #[serde(rename = "chat")]
+    Chat { sender: Player, content: String },
+    #[serde(rename = "status")]
+    Status {
+        #[serde(default)]
+        players: Option<Vec<Player>>,
+        #[serde(default)]
+        games: Option<Vec<Game>>,
+    },
+    /// Adds and removes individual games (by name) and players (by id) rather than replacing the
+    /// whole status. Adding a game or player that is already present replaces it.
+    #[serde(rename = "status_delta")]
+    StatusDelta {
+        #[serde(default)]
+        add_players: Vec<Player>,
+        #[serde(default)]
+        remove_players: Vec<String>,
+        #[serde(default)]
+        add_games: Vec<Game>,
+        #[serde(default)]
+        remove_games: Vec<String>,
+    },
+    #[serde(rename = "lifecycle_start")]
+    LifecycleStart {},
+    #[serde(rename = "lifecycle_stop")]
+    LifecycleStop { crash: bool },
+    #[serde(rename = "performance")]
+    Performance(ServerPerformance),
+    #[serde(rename = "system")]
+    SystemMessage { content: String },
+    #[serde(rename = "upload_statistics")]
+    /// Servers may either choose the game id themselves, or leave it out (or send the nil id) to
+    /// have the backend allocate one, which is sent back in `StatisticsUploaded`.
+    UploadStatistics {
+        bundle: GameStatsBundle,
+        #[serde(default)]
+        game_id: Option<Uuid>,
+        /// Validate the bundle and send it back without storing it.
+        #[serde(default)]
+        dry_run: bool,
+    },
+    #[serde(rename = "query_config")]
+    QueryConfig {},
+    /// Updates the details given in the handshake without reconnecting. Absent fields are left
+    /// unchanged.
+    #[serde(rename = "update_server_info")]
+    UpdateServerInfo {
+        #[serde(default)]
+        server_ip: Option<String>,
+        #[serde(default)]
+        game_version: Option<String>,
+    },
+}
+
+/// A message sent from the backend to an integrations client.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "body")]
+pub enum OutgoingMessage {
+    #[serde(rename = "chat")]
+    Chat(ChatMessage),
+    #[serde(rename = "command")]
+    Command {
+        command: String,
+        sender: String,
+        roles: Vec<String>,
+        silent: bool,
+    },
+    #[serde(rename = "send_to_server")]
+    SendToServer {
+        // The UUID
+        player: String,
+        target_server: String,
+    },
+    #[serde(rename = "send_server_to_server")]
+    SendServerToServer {
+        from_server: String,
+        to_server: String,
+    },
+    #[serde(rename = "config")]
+    Config(ChannelConfig),
+    #[serde(rename = "statistics_dry_run")]
+    StatisticsDryRun {
+        game_id: Uuid,
+        bundle: GameStatsBundle,
+    },
+    /// Sent when an uploaded statistics bundle was not stored, so the server can retry later.
+    #[serde(rename = "statistics_rejected")]
+    StatisticsRejected { game_id: Uuid, reason: String },
+    /// Sent once a bundle uploaded without a game id has been stored, with the id it was given.
+    #[serde(rename = "statistics_uploaded")]
+    StatisticsUploaded { game_id: Uuid },
+}
+
+/// Builds the codec that splits a byte stream into message frames.
+pub fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .big_endian()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .length_field_length(FRAME_HEADER_SIZE)
+        .num_skip(FRAME_HEADER_SIZE)
+        .length_field_offset(0)
+        .length_adjustment(0)
+        .new_codec()
+}
+
+/// Splits a connection into a sink of messages to send and a stream of messages received.
+///
+/// The server sends [`OutgoingMessage`] and receives [`IncomingMessage`]; a client does the
+/// opposite.
+pub fn split_framed<T, Tx, Rx, E>(
+    io: T,
+) -> (
+    impl Sink<Tx, Error = E> + Send,
+    impl Stream<Item = Result<Rx, E>>,
+)
+where
+    T: AsyncRead + AsyncWrite + Send,
+    Tx: Serialize + Send,
+    Rx: DeserializeOwned,
+    E: From<io::Error> + From<serde_json::Error> + Send,
+{
+    let (sink, stream) = tokio_util::codec::Framed::new(io, codec()).split();
+
+    let sink = sink.with(|message: Tx| async move {
+        let mut bytes = Vec::with_capacity(64);
+        serde_json::to_writer(&mut bytes, &message)?;
+        Ok(Bytes::from(bytes))
+    });
+
+    let stream = stream.map(|result| match result {
+        Ok(bytes) => serde_json::from_slice(bytes.as_ref()).map_err(E::from),
+        Err(err) => Err(err.into()),
+    });
+
+    (sink, stream)
+}
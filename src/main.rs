@@ -21,15 +21,29 @@ mod web;
 
 #[tokio::main]
 async fn main() {
+    // LOG_FORMAT=json switches to structured logs for ingestion by log aggregators
+    let (text_layer, json_layer) = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => (None, Some(tracing_subscriber::fmt::layer().json())),
+        _ => (Some(tracing_subscriber::fmt::layer()), None),
+    };
+
     tracing_subscriber::registry()
         .with(tracing_subscriber::filter::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "debug,serenity=info,rustls=info,h2=info,hyper=info".into()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(text_layer)
+        .with(json_layer)
         .init();
 
     let config = config::load();
-    let controller = xtra::spawn_tokio(Controller::new(config.clone()).await, Mailbox::unbounded());
+    let controller = match Controller::new(config.clone()).await {
+        Ok(controller) => controller,
+        Err(e) => {
+            tracing::error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let controller = xtra::spawn_tokio(controller, Mailbox::unbounded());
 
     let mut futures = Vec::with_capacity(5);
 
@@ -1,5 +1,8 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::Kickback;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ServerStatus {
     pub game_version: String,
@@ -9,7 +12,7 @@ pub struct ServerStatus {
     pub players: Vec<Player>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerType {
     Minecraft,
     Velocity,
@@ -19,6 +22,9 @@ pub enum ServerType {
 pub struct Player {
     pub id: String,
     pub name: String,
+    /// The RGB color of the player's name, e.g. from their rank.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_color: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -29,7 +35,7 @@ pub struct Game {
     pub player_count: u16,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ChatMessage {
     pub sender: String,
     pub sender_user: DiscordUser,
@@ -41,16 +47,59 @@ pub struct ChatMessage {
     pub replying_to: Option<Box<ChatMessage>>,
 }
 
-#[derive(Serialize, Debug)]
+/// A chat message relayed through a channel, kept so that recent chat can be fetched over HTTP.
+#[derive(Serialize, Clone, Debug)]
+pub struct RecentChat {
+    pub sender: String,
+    pub content: String,
+    pub source: ChatSource,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatSource {
+    Minecraft,
+    Discord,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct DiscordUser {
     pub id: u64,
     pub name: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ChatAttachment {
     pub name: String,
     pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// The size of the file in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    /// Only present for images and videos.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    /// Only present for images and videos.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorSeverity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+/// What the backend knows about a channel, sent to an integrations client on request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelConfig {
+    pub relay_attached: bool,
+    pub kickback: Option<Kickback>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -1,72 +1,297 @@
-use std::{num::NonZeroUsize, time::Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
 
 use lru::LruCache;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 use xtra::{Actor, Address, Context, Handler, Mailbox};
 
+use crate::Persistent;
+
 const USER_AGENT: &str = "nucleoid-backend (v1, https://github.com/NucleoidMC/nucleoid-backend)";
 const MOJANG_PROFILE_URL: &str = "https://sessionserver.mojang.com/session/minecraft/profile";
 
-const CACHE_CLEAR_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+/// How long to remember that a profile does not exist before asking Mojang again.
+const MISSING_PROFILE_TTL: Duration = Duration::from_secs(60 * 10);
+
+const MAX_SEARCH_RESULTS: usize = 10;
+
+/// The most players the username index holds. Once full, players who aren't indexed yet are left
+/// out, while the names of indexed players are still kept up to date.
+const MAX_INDEXED_USERNAMES: usize = 200_000;
+
+/// How often changes to the username index are written to disk. Writing is batched as it rewrites
+/// the whole file, so changes made within this long of a crash are lost.
+const USERNAME_INDEX_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Which account system a player belongs to.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Java,
+    Bedrock,
+}
+
+impl Platform {
+    /// Guesses the platform of a player from their UUID. Geyser gives Bedrock players a UUID
+    /// whose upper half is zero and whose lower half is their XUID.
+    fn guess(uuid: &Uuid) -> Platform {
+        if uuid.as_u64_pair().0 == 0 {
+            Platform::Bedrock
+        } else {
+            Platform::Java
+        }
+    }
+}
 
 #[derive(Actor)]
 pub struct MojangApiClient {
     client: Client,
-    username_cache: LruCache<Uuid, String>,
+    /// Usernames along with when they were fetched, as players can change their name.
+    username_cache: LruCache<Uuid, (Instant, String)>,
+    username_ttl: Duration,
+    /// The URL that Bedrock gamertags are resolved from, given a player's XUID.
+    bedrock_username_url: Option<String>,
+    missing_cache: LruCache<Uuid, Instant>,
+    username_index: Persistent<UsernameIndex>,
+    /// Whether the username index has changed since it was last written to disk.
+    username_index_dirty: bool,
+}
+
+/// Every username that has been resolved through this client, used for searching players by name.
+///
+/// Mojang provides no search API, so this only ever contains players the backend has seen before.
+#[derive(Default)]
+struct UsernameIndex {
+    names: HashMap<Uuid, String>,
+    /// The same names keyed by their lowercase form, so that a prefix search is a range lookup.
+    by_name: BTreeMap<(String, Uuid), String>,
+}
+
+impl UsernameIndex {
+    /// Returns whether the index changed.
+    fn insert(&mut self, uuid: Uuid, name: &str) -> bool {
+        match self.names.get(&uuid) {
+            Some(old) if old == name => return false,
+            Some(old) => {
+                self.by_name.remove(&(old.to_lowercase(), uuid));
+            }
+            None if self.names.len() >= MAX_INDEXED_USERNAMES => return false,
+            None => {}
+        }
+        self.names.insert(uuid, name.to_owned());
+        self.by_name
+            .insert((name.to_lowercase(), uuid), name.to_owned());
+        true
+    }
+
+    /// Returns whether the index changed.
+    fn remove(&mut self, uuid: &Uuid) -> bool {
+        match self.names.remove(uuid) {
+            Some(name) => {
+                self.by_name.remove(&(name.to_lowercase(), *uuid));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finds the players whose names start with the prefix, in alphabetical order.
+    fn search(&self, prefix: &str) -> Vec<ProfileResponse> {
+        let prefix = prefix.to_lowercase();
+        self.by_name
+            .range((prefix.clone(), Uuid::nil())..)
+            .take_while(|((name, _), _)| name.starts_with(&prefix))
+            .take(MAX_SEARCH_RESULTS)
+            .map(|((_, id), name)| ProfileResponse {
+                id: *id,
+                name: name.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Serialize for UsernameIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.names.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UsernameIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names: HashMap<Uuid, String> = HashMap::deserialize(deserializer)?;
+        let by_name = names
+            .iter()
+            .map(|(uuid, name)| ((name.to_lowercase(), *uuid), name.clone()))
+            .collect();
+        Ok(UsernameIndex { names, by_name })
+    }
 }
 
 impl MojangApiClient {
-    pub fn start(cache_size: NonZeroUsize) -> Result<Address<Self>, ClientError> {
+    pub async fn start(
+        cache_size: NonZeroUsize,
+        username_ttl: Duration,
+        request_timeout: Duration,
+        bedrock_username_url: Option<String>,
+    ) -> Result<Address<Self>, ClientError> {
         let username_cache = LruCache::new(cache_size);
+        let missing_cache = LruCache::new(cache_size);
         let client = Self {
-            client: Client::builder().user_agent(USER_AGENT).build()?,
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .timeout(request_timeout)
+                .build()?,
             username_cache,
+            username_ttl,
+            bedrock_username_url,
+            missing_cache,
+            username_index: Persistent::open("usernames.json").await,
+            username_index_dirty: false,
         };
 
-        let client = xtra::spawn_tokio(client, Mailbox::unbounded());
+        let address = xtra::spawn_tokio(client, Mailbox::unbounded());
 
-        let client_weak = client.downgrade();
-        // Based on https://github.com/NucleoidMC/player-face-api/blob/main/src/api.rs#L52-L63
+        let weak = address.downgrade();
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(CACHE_CLEAR_INTERVAL);
+            let mut interval = tokio::time::interval(USERNAME_INDEX_FLUSH_INTERVAL);
             loop {
                 interval.tick().await;
-                if client_weak.send(ClearCache).await.is_err() {
+                if weak.send(FlushUsernameIndex).await.is_err() {
                     break;
                 }
             }
         });
 
-        Ok(client)
+        Ok(address)
     }
 
-    async fn get_username(&mut self, uuid: &Uuid) -> Result<Option<String>, ClientError> {
-        if let Some(username) = self.username_cache.get(uuid) {
-            Ok(Some(username.clone()))
+    async fn get_username(
+        &mut self,
+        uuid: &Uuid,
+        platform: Option<Platform>,
+    ) -> Result<Option<String>, ClientError> {
+        if let Some(username) = self.cached_username(uuid) {
+            return Ok(Some(username));
+        } else if self.is_known_missing(uuid) {
+            return Ok(None);
+        }
+
+        // Bedrock players can only be resolved if we have somewhere to resolve them from
+        let bedrock_url = self
+            .bedrock_username_url
+            .clone()
+            .filter(|_| platform.unwrap_or_else(|| Platform::guess(uuid)) == Platform::Bedrock);
+
+        let username = match bedrock_url {
+            Some(url) => self.fetch_bedrock_username(&url, uuid).await?,
+            None => self.fetch_java_username(uuid).await?,
+        };
+
+        match &username {
+            Some(username) => {
+                self.username_cache
+                    .put(*uuid, (Instant::now(), username.clone()));
+                self.index_username(*uuid, username);
+            }
+            None => {
+                self.missing_cache.put(*uuid, Instant::now());
+            }
+        }
+
+        Ok(username)
+    }
+
+    async fn fetch_java_username(&self, uuid: &Uuid) -> Result<Option<String>, ClientError> {
+        let response = self
+            .client
+            .get(format!("{}/{}", MOJANG_PROFILE_URL, uuid))
+            .send()
+            .await?;
+        let status = response.status();
+        if status == StatusCode::NO_CONTENT {
+            // mojang why don't you just return a 404 here :/
+            Ok(None)
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            Err(rate_limited(&response))
+        } else if !status.is_success() {
+            Err(ClientError::UnexpectedStatus(status))
         } else {
-            let response = self
-                .client
-                .get(format!("{}/{}", MOJANG_PROFILE_URL, uuid))
-                .send()
-                .await?;
-            if response.status().as_u16() == 204 {
-                // mojang why don't you just return a 404 here :/
-                Ok(None)
-            } else {
-                let profile = response.json::<ProfileResponse>().await?;
-                let username = profile.name;
-                self.username_cache.put(*uuid, username.clone());
-                Ok(Some(username))
+            let profile = response.json::<ProfileResponse>().await?;
+            Ok(Some(profile.name))
+        }
+    }
+
+    async fn fetch_bedrock_username(
+        &self,
+        url: &str,
+        uuid: &Uuid,
+    ) -> Result<Option<String>, ClientError> {
+        let xuid = uuid.as_u64_pair().1;
+        let response = self.client.get(format!("{}/{}", url, xuid)).send().await?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND || status == StatusCode::NO_CONTENT {
+            Ok(None)
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            Err(rate_limited(&response))
+        } else if !status.is_success() {
+            Err(ClientError::UnexpectedStatus(status))
+        } else {
+            let response = response.json::<GamertagResponse>().await?;
+            Ok(response.gamertag)
+        }
+    }
+
+    /// Entries expire individually so that the cache never empties all at once, which would
+    /// send a burst of requests to Mojang.
+    fn cached_username(&mut self, uuid: &Uuid) -> Option<String> {
+        match self.username_cache.get(uuid) {
+            Some((time, username)) if time.elapsed() < self.username_ttl => Some(username.clone()),
+            Some(_) => {
+                self.username_cache.pop(uuid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn is_known_missing(&mut self, uuid: &Uuid) -> bool {
+        match self.missing_cache.get(uuid) {
+            Some(time) if time.elapsed() < MISSING_PROFILE_TTL => true,
+            Some(_) => {
+                self.missing_cache.pop(uuid);
+                false
             }
+            None => false,
+        }
+    }
+
+    /// Changes are written to disk later by `FlushUsernameIndex`.
+    fn index_username(&mut self, uuid: Uuid, username: &str) {
+        if self
+            .username_index
+            .get_mut_unchecked()
+            .insert(uuid, username)
+        {
+            self.username_index_dirty = true;
         }
     }
 }
 
-pub struct GetPlayerUsername(pub Uuid);
+/// Resolves a player's username. The platform is guessed from the UUID if not given.
+pub struct GetPlayerUsername(pub Uuid, pub Option<Platform>);
 
-struct ClearCache;
+/// Searches for players whose username starts with the given prefix, case-insensitively.
+pub struct SearchPlayers(pub String);
+
+/// Forgets everything known about a player, e.g. after their data has been deleted.
+pub struct ForgetPlayer(pub Uuid);
+
+/// Writes the username index to disk if it has changed.
+struct FlushUsernameIndex;
 
 impl Handler<GetPlayerUsername> for MojangApiClient {
     type Return = Result<Option<ProfileResponse>, ClientError>;
@@ -76,7 +301,7 @@ impl Handler<GetPlayerUsername> for MojangApiClient {
         message: GetPlayerUsername,
         _ctx: &mut Context<Self>,
     ) -> Self::Return {
-        let username = self.get_username(&message.0).await?;
+        let username = self.get_username(&message.0, message.1).await?;
         Ok(username.map(|username| ProfileResponse {
             id: message.0,
             name: username,
@@ -84,11 +309,36 @@ impl Handler<GetPlayerUsername> for MojangApiClient {
     }
 }
 
-impl Handler<ClearCache> for MojangApiClient {
+impl Handler<SearchPlayers> for MojangApiClient {
+    type Return = Vec<ProfileResponse>;
+
+    async fn handle(&mut self, message: SearchPlayers, _ctx: &mut Context<Self>) -> Self::Return {
+        self.username_index.search(&message.0)
+    }
+}
+
+impl Handler<ForgetPlayer> for MojangApiClient {
     type Return = ();
 
-    async fn handle(&mut self, _message: ClearCache, _ctx: &mut Context<Self>) -> Self::Return {
-        self.username_cache.clear();
+    async fn handle(&mut self, message: ForgetPlayer, _ctx: &mut Context<Self>) -> Self::Return {
+        self.username_cache.pop(&message.0);
+        self.missing_cache.pop(&message.0);
+        // Written straight away, as the player asked for their data to be removed
+        if self.username_index.get_mut_unchecked().remove(&message.0) {
+            self.username_index.flush().await;
+            self.username_index_dirty = false;
+        }
+    }
+}
+
+impl Handler<FlushUsernameIndex> for MojangApiClient {
+    type Return = ();
+
+    async fn handle(&mut self, _: FlushUsernameIndex, _ctx: &mut Context<Self>) -> Self::Return {
+        if self.username_index_dirty {
+            self.username_index.flush().await;
+            self.username_index_dirty = false;
+        }
     }
 }
 
@@ -98,8 +348,85 @@ pub struct ProfileResponse {
     name: String,
 }
 
+#[derive(Deserialize)]
+struct GamertagResponse {
+    gamertag: Option<String>,
+}
+
+fn rate_limited(response: &reqwest::Response) -> ClientError {
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    ClientError::RateLimited { retry_after }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
     #[error("request error: {0}")]
-    ReqwestError(#[from] reqwest::Error),
+    ReqwestError(#[source] reqwest::Error),
+    #[error("timed out waiting for the Mojang API")]
+    Timeout,
+    #[error("rate limited by the Mojang API")]
+    RateLimited { retry_after: Option<u64> },
+    #[error("unexpected response status from the Mojang API: {0}")]
+    UnexpectedStatus(StatusCode),
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            ClientError::Timeout
+        } else {
+            ClientError::ReqwestError(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(results: Vec<ProfileResponse>) -> Vec<String> {
+        results.into_iter().map(|profile| profile.name).collect()
+    }
+
+    #[test]
+    fn searches_by_prefix_ignoring_case() {
+        let mut index = UsernameIndex::default();
+        index.insert(Uuid::from_u128(1), "Steve");
+        index.insert(Uuid::from_u128(2), "stevenson");
+        index.insert(Uuid::from_u128(3), "Alex");
+
+        assert_eq!(names(index.search("STE")), vec!["Steve", "stevenson"]);
+        assert_eq!(names(index.search("alex")), vec!["Alex"]);
+        assert!(index.search("z").is_empty());
+    }
+
+    #[test]
+    fn renames_replace_the_old_name() {
+        let mut index = UsernameIndex::default();
+        assert!(index.insert(Uuid::from_u128(1), "Steve"));
+        assert!(!index.insert(Uuid::from_u128(1), "Steve"));
+        assert!(index.insert(Uuid::from_u128(1), "Herobrine"));
+
+        assert!(index.search("steve").is_empty());
+        assert_eq!(names(index.search("hero")), vec!["Herobrine"]);
+
+        assert!(index.remove(&Uuid::from_u128(1)));
+        assert!(index.search("hero").is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_the_stored_format() {
+        let mut index = UsernameIndex::default();
+        index.insert(Uuid::from_u128(1), "Steve");
+
+        let json = serde_json::to_string(&index).unwrap();
+        assert_eq!(json, format!(r#"{{"{}":"Steve"}}"#, Uuid::from_u128(1)));
+
+        let index: UsernameIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(names(index.search("st")), vec!["Steve"]);
+    }
 }
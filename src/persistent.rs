@@ -1,10 +1,20 @@
+use std::io::{Read, Write};
 use std::ops::Deref;
 use std::path::PathBuf;
 
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// Whether stores are gzip-compressed when written. Compressed files are detected when opening
+/// regardless of this, so it can be toggled without losing existing stores.
+const COMPRESS_STORES: bool = false;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 pub trait Persistable: Serialize + DeserializeOwned + Default {}
 
 impl<T: Serialize + DeserializeOwned + Default> Persistable for T {}
@@ -28,6 +38,15 @@ impl<T: Persistable> Persistent<T> {
                 .await
                 .expect("failed to load persistent file");
 
+            // JSON can't start with these bytes, so they can only be from a compressed file
+            if bytes.starts_with(&GZIP_MAGIC) {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(&bytes[..])
+                    .read_to_end(&mut decompressed)
+                    .expect("failed to decompress persistent file");
+                bytes = decompressed;
+            }
+
             serde_json::from_slice(&bytes).expect("failed to deserialize persistent file")
         } else {
             T::default()
@@ -57,7 +76,17 @@ impl<T: Persistable> Persistent<T> {
             .await
             .expect("failed to create persistent file");
 
-        let bytes = serde_json::to_vec(&self.inner).expect("failed to serialize persistent file");
+        let mut bytes =
+            serde_json::to_vec(&self.inner).expect("failed to serialize persistent file");
+        if COMPRESS_STORES {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&bytes)
+                .expect("failed to compress persistent file");
+            bytes = encoder
+                .finish()
+                .expect("failed to compress persistent file");
+        }
         file.write_all(&bytes)
             .await
             .expect("failed to write to persistent file");
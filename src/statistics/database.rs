@@ -1,30 +1,91 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
 
 use chrono::{DateTime, NaiveDate, Utc};
 use chrono_tz::Tz;
-use clickhouse_rs::{row, Block, Pool};
-use tracing::warn;
-use nucleoid_leaderboards::model::LeaderboardDefinition;
+use clickhouse_rs::types::{Complex, Row, Simple};
+use clickhouse_rs::{row, Block, ClientHandle, Pool};
+use futures::{future, Stream, TryStreamExt};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 use xtra::{Actor, Address, Context, Handler};
 
-use crate::statistics::leaderboards::database::LeaderboardsDatabase;
-use crate::statistics::leaderboards::LeaderboardEntry;
+use crate::mojang_api::{ClientError, GetPlayerUsername};
+use crate::statistics::leaderboards::database::{LeaderboardsDatabase, DEFAULT_LEADERBOARD_LIMIT};
+use crate::statistics::leaderboards::{
+    LeaderboardEntry, LeaderboardOrder, LeaderboardWithPlayer, LoadedLeaderboard,
+};
 use crate::statistics::model::{
-    initialise_database, DataQueryType, Datapoint, GameStatsBundle, PlayerStatsResponse,
-    RecentGame, StatisticCounts, StatisticsStats,
+    initialise_database, is_valid_namespace, DataQueryResult, DataQueryType, Datapoint,
+    DeletedPlayerStats, GameMetadata, GameStatsBundle, GameStatsResponse, IngestionCounts,
+    IngestionRate, PlayerCountBucket, PlayerStatsResponse, RecentGame, StatisticCounts,
+    StatisticsStats, UniquePlayersDatapoint,
 };
-use crate::{Controller, StatisticsConfig};
+use crate::{Controller, GetMojangApiClient, StatisticsConfig};
 
 use super::wrapped::{NucleoidWrapped, PlayerWrappedData};
 
+/// How long the list of servers is cached for. New servers appear rarely, so this can be long.
+const SERVERS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The window that the ingestion rate is measured over.
+pub const INGESTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Runs a query, logging its SQL so that a failing query can be told apart from the others.
+pub(super) async fn query_logged(
+    handle: &mut ClientHandle,
+    sql: impl AsRef<str>,
+) -> Result<Block<Complex>, clickhouse_rs::errors::Error> {
+    let sql = sql.as_ref();
+    debug!("running query: {}", sql);
+    handle.query(sql).fetch_all().await.map_err(|e| {
+        warn!("query failed: {}\n{}", e, sql);
+        e
+    })
+}
+
+/// Like `query_logged`, but streams the rows as they arrive instead of collecting them.
+pub(super) fn query_stream_logged<'a>(
+    handle: &'a mut ClientHandle,
+    sql: &str,
+) -> impl Stream<Item = Result<Row<'static, Simple>, clickhouse_rs::errors::Error>> + 'a {
+    debug!("running query: {}", sql);
+    let logged_sql = sql.to_owned();
+    handle.query(sql).stream().map_err(move |e| {
+        warn!("query failed: {}\n{}", e, logged_sql);
+        e
+    })
+}
+
+/// Runs a statement that returns no rows, such as a mutation, logging its SQL like
+/// `query_logged`.
+pub(super) async fn execute_logged(
+    handle: &mut ClientHandle,
+    sql: impl AsRef<str>,
+) -> Result<(), clickhouse_rs::errors::Error> {
+    let sql = sql.as_ref();
+    debug!("running statement: {}", sql);
+    handle.execute(sql).await.map_err(|e| {
+        warn!("statement failed: {}\n{}", e, sql);
+        e
+    })
+}
+
 #[derive(Actor)]
 pub struct StatisticDatabaseController {
-    _controller: Address<Controller>,
+    controller: Address<Controller>,
     pool: Pool,
-    _config: StatisticsConfig,
+    config: StatisticsConfig,
+    timezone: Tz,
     leaderboards: LeaderboardsDatabase,
     wrapped: NucleoidWrapped,
+    servers_cache: Option<(Instant, Vec<String>)>,
+    stats_cache: Option<(Instant, StatisticsStats)>,
+    /// Bundles uploaded within the ingestion window, oldest first.
+    recent_uploads: VecDeque<(Instant, String, IngestionCounts)>,
+    /// The task fetching the usernames of top-ranked players, if one is running.
+    username_warmer: Option<JoinHandle<()>>,
 }
 
 impl StatisticDatabaseController {
@@ -32,25 +93,51 @@ impl StatisticDatabaseController {
         controller: &Address<Controller>,
         postgres_pool: deadpool_postgres::Pool,
         config: &StatisticsConfig,
-        leaderboards: Vec<LeaderboardDefinition>,
+        leaderboards: Vec<LoadedLeaderboard>,
     ) -> StatisticsDatabaseResult<Self> {
         let pool = Pool::new(config.database_url.clone());
+        let timezone = config
+            .timezone
+            .parse::<Tz>()
+            .map_err(|_| StatisticsDatabaseError::InvalidTimezone(config.timezone.clone()))?;
 
         let wrapped = NucleoidWrapped::new(pool.clone());
 
-        let handler = Self {
-            _controller: controller.clone(),
+        let mut handler = Self {
+            controller: controller.clone(),
             pool: pool.clone(),
-            _config: config.clone(),
-            leaderboards: LeaderboardsDatabase::new(postgres_pool.clone(), pool, leaderboards)
-                .await?,
+            config: config.clone(),
+            timezone,
+            leaderboards: LeaderboardsDatabase::new(
+                postgres_pool.clone(),
+                pool,
+                leaderboards,
+                config.leaderboard_rebuild_concurrency,
+                config.leaderboard_tie_ranking,
+            )
+            .await?,
             wrapped,
+            servers_cache: None,
+            stats_cache: None,
+            recent_uploads: VecDeque::new(),
+            username_warmer: None,
         };
 
         initialise_database(&handler.pool).await?;
 
-        // Force-rebuild leaderboards at startup to ensure they are up-to-date
-        handler.leaderboards.update_all_leaderboards().await?;
+        // Rebuild leaderboards at startup to ensure they are up-to-date, unless they recently were
+        match config.startup_rebuild_freshness_seconds {
+            Some(freshness) => {
+                let max_age = Duration::from_secs(freshness);
+                let rebuilt = handler
+                    .leaderboards
+                    .update_stale_leaderboards(max_age)
+                    .await?;
+                info!("rebuilt {} stale leaderboards at startup", rebuilt);
+            }
+            None => handler.leaderboards.update_all_leaderboards().await?,
+        }
+        handler.warm_usernames().await;
 
         Ok(handler)
     }
@@ -59,6 +146,7 @@ impl StatisticDatabaseController {
         &self,
         player_id: &Uuid,
         namespace: &Option<String>,
+        all_keys: bool,
     ) -> StatisticsDatabaseResult<Option<PlayerStatsResponse>> {
         let mut handle = self.pool.get_handle().await?;
 
@@ -88,7 +176,7 @@ impl StatisticDatabaseController {
             cond
         );
 
-        let block = handle.query(sql).fetch_all().await?;
+        let block = query_logged(&mut handle, sql).await?;
 
         let mut result = HashMap::new();
         for row in block.rows() {
@@ -101,6 +189,14 @@ impl StatisticDatabaseController {
             result.get_mut(&namespace).unwrap().insert(key, value);
         }
 
+        if let (true, Some(namespace)) = (all_keys, namespace) {
+            if let Some(stats) = result.get_mut(namespace) {
+                for key in self.get_namespace_keys(namespace).await? {
+                    stats.entry(key).or_insert(0.0);
+                }
+            }
+        }
+
         if result.is_empty() {
             Ok(None)
         } else {
@@ -108,13 +204,37 @@ impl StatisticDatabaseController {
         }
     }
 
+    /// Lists every player statistic key that has ever been recorded in the namespace.
+    async fn get_namespace_keys(&self, namespace: &str) -> StatisticsDatabaseResult<Vec<String>> {
+        let mut handle = self.pool.get_handle().await?;
+
+        // Namespaces are validated by the caller, so are safe to insert into the query
+        let sql = format!(
+            "SELECT DISTINCT key FROM player_statistics WHERE namespace = '{}'",
+            namespace
+        );
+        let block = query_logged(&mut handle, sql).await?;
+
+        let mut keys = Vec::new();
+        for row in block.rows() {
+            keys.push(row.get("key")?);
+        }
+        Ok(keys)
+    }
+
     async fn get_recent_games(
         &self,
         limit: u32,
         player_id: Option<Uuid>,
+        since: Option<DateTime<Utc>>,
     ) -> StatisticsDatabaseResult<Vec<RecentGame>> {
         let mut handle = self.pool.get_handle().await?;
 
+        let since_filter = match since {
+            Some(since) => format!("date_played >= toDateTime({})", since.timestamp()),
+            None => "1".to_owned(),
+        };
+
         let sql = match player_id {
             Some(player_id) => format!(
                 r#"
@@ -123,36 +243,39 @@ impl StatisticDatabaseController {
                 namespace,
                 player_count,
                 server,
-                date_played
+                date_played,
+                duration_seconds
             FROM
                 player_statistics
             INNER JOIN games
                 ON player_statistics.game_id=games.game_id
             WHERE
-                player_id = '{}'
+                player_id = '{}' AND {}
             GROUP BY
                 game_id,
                 namespace,
                 player_count,
                 server,
-                date_played
+                date_played,
+                duration_seconds
             ORDER BY date_played DESC
             LIMIT {}
             "#,
-                player_id, limit
+                player_id, since_filter, limit
             ),
             None => format!(
                 r#"
                 SELECT *
                 FROM games
+                WHERE {}
                 ORDER BY date_played DESC
                 LIMIT {}
                 "#,
-                limit
+                since_filter, limit
             ),
         };
 
-        let games_res = handle.query(sql).fetch_all().await?;
+        let games_res = query_logged(&mut handle, sql).await?;
 
         if games_res.is_empty() {
             return Ok(Vec::with_capacity(0));
@@ -166,70 +289,105 @@ impl StatisticDatabaseController {
             let player_count: u32 = row.get("player_count")?;
             let server: String = row.get("server")?;
             let date_played: DateTime<Tz> = row.get("date_played")?;
-
-            let players_sql = format!(
-                r#"
-            SELECT player_id
-            FROM player_statistics
-            WHERE game_id = '{}'
-            GROUP BY player_id
-            "#,
-                game_id
-            );
-
-            let players_res = handle.query(players_sql).fetch_all().await?;
-            let mut players = Vec::with_capacity(player_count as usize);
-            for player_id in players_res
-                .rows()
-                .map(|row| row.get::<Uuid, _>("player_id"))
-            {
-                players.push(player_id?);
-            }
+            let duration_seconds: Option<u32> = row.get("duration_seconds")?;
 
             games.push(RecentGame {
                 id: game_id,
                 namespace,
-                players,
+                players: Vec::with_capacity(player_count as usize),
                 server,
                 date_played: date_played.with_timezone(&Utc),
+                duration_seconds,
             });
         }
 
+        // Fetch the players of every game at once rather than querying once per game.
+        let game_ids = games
+            .iter()
+            .map(|game| format!("'{}'", game.id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let players_sql = format!(
+            r#"
+            SELECT game_id, player_id
+            FROM player_statistics
+            WHERE game_id IN ({})
+            GROUP BY game_id, player_id
+            "#,
+            game_ids
+        );
+
+        let players_res = query_logged(&mut handle, players_sql).await?;
+        let mut players_by_game: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        for row in players_res.rows() {
+            let game_id: Uuid = row.get("game_id")?;
+            let player_id: Uuid = row.get("player_id")?;
+            players_by_game.entry(game_id).or_default().push(player_id);
+        }
+
+        for game in &mut games {
+            if let Some(players) = players_by_game.remove(&game.id) {
+                game.players = players;
+            }
+        }
+
         Ok(games)
     }
 
     async fn get_game_stats(
         &self,
         game_id: &Uuid,
-    ) -> StatisticsDatabaseResult<Option<HashMap<Uuid, PlayerStatsResponse>>> {
+        namespace: &Option<String>,
+    ) -> StatisticsDatabaseResult<Option<GameStatsResponse>> {
         let mut handle = self.pool.get_handle().await?;
 
-        let game_sql = format!("SELECT game_id FROM games WHERE game_id = '{}'", game_id);
+        let game_sql = format!(
+            r#"
+            SELECT namespace, server, player_count, date_played, duration_seconds
+            FROM games
+            WHERE game_id = '{}'
+            "#,
+            game_id
+        );
 
-        if handle.query(game_sql).fetch_all().await?.is_empty() {
-            return Ok(None);
-        }
+        let game = match query_logged(&mut handle, game_sql).await?.rows().next() {
+            Some(row) => {
+                let date_played: DateTime<Tz> = row.get("date_played")?;
+                GameMetadata {
+                    namespace: row.get("namespace")?,
+                    server: row.get("server")?,
+                    player_count: row.get("player_count")?,
+                    date_played: date_played.with_timezone(&Utc),
+                    duration_seconds: row.get("duration_seconds")?,
+                }
+            }
+            None => return Ok(None),
+        };
 
         // This should be safe, as although a uuid is potentially-untrusted user input,
         // they are strictly formed and so no escape characters can be used to break out
-        // of the sql string and manipulate the query.
+        // of the sql string and manipulate the query. Namespaces are validated by the caller.
+        let cond = match namespace {
+            Some(namespace) => format!("game_id = '{}' AND namespace = '{}'", game_id, namespace),
+            None => format!("game_id = '{}'", game_id),
+        };
         let players_sql = format!(
             r#"
             SELECT player_id, namespace, key, value, type
                 FROM player_statistics
-                WHERE game_id = '{}'"#,
-            game_id
+                WHERE {}"#,
+            cond
         );
         let global_sql = format!(
             r#"
             SELECT namespace, key, value, type
                 FROM global_statistics
-                WHERE game_id = '{}'"#,
-            game_id
+                WHERE {}"#,
+            cond
         );
 
-        let players_res = handle.query(players_sql).fetch_all().await?;
-        let global_res = handle.query(global_sql).fetch_all().await?;
+        let players_res = query_logged(&mut handle, players_sql).await?;
+        let global_res = query_logged(&mut handle, global_sql).await?;
 
         if players_res.is_empty() && global_res.is_empty() {
             return Ok(None);
@@ -251,34 +409,265 @@ impl StatisticDatabaseController {
             stats.insert(key, value);
         }
 
-        let global_player_id = Uuid::nil();
+        let mut global = HashMap::new();
         for row in global_res.rows() {
             let namespace: String = row.get("namespace")?;
             let key: String = row.get("key")?;
             let value: f64 = row.get("value")?;
             // let stat_type: String = row.get("type")?;
-            let player_stats = players.entry(global_player_id).or_insert_with(HashMap::new);
-            if !player_stats.contains_key(&namespace) {
-                player_stats.insert(namespace.clone(), HashMap::new());
+            if !global.contains_key(&namespace) {
+                global.insert(namespace.clone(), HashMap::new());
             }
-            let stats = player_stats.get_mut(&namespace).unwrap();
+            let stats = global.get_mut(&namespace).unwrap();
             stats.insert(key, value);
         }
 
-        Ok(Some(players))
+        Ok(Some(GameStatsResponse {
+            game,
+            players,
+            global,
+        }))
+    }
+
+    /// Like [`Self::get_game_stats`] for several games at once. Games which don't exist or have no
+    /// statistics are left out of the result.
+    async fn get_games_stats(
+        &self,
+        game_ids: &[Uuid],
+    ) -> StatisticsDatabaseResult<HashMap<Uuid, GameStatsResponse>> {
+        if game_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+
+        // As above, uuids are strictly formed and so are safe to put in the query directly
+        let game_ids = game_ids
+            .iter()
+            .map(|id| format!("'{}'", id))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let games_sql = format!(
+            r#"
+            SELECT game_id, namespace, server, player_count, date_played, duration_seconds
+            FROM games
+            WHERE game_id IN ({})
+            "#,
+            game_ids
+        );
+        let players_sql = format!(
+            r#"
+            SELECT game_id, player_id, namespace, key, value
+                FROM player_statistics
+                WHERE game_id IN ({})"#,
+            game_ids
+        );
+        let global_sql = format!(
+            r#"
+            SELECT game_id, namespace, key, value
+                FROM global_statistics
+                WHERE game_id IN ({})"#,
+            game_ids
+        );
+
+        let mut games = HashMap::new();
+        for row in query_logged(&mut handle, games_sql).await?.rows() {
+            let game_id: Uuid = row.get("game_id")?;
+            let date_played: DateTime<Tz> = row.get("date_played")?;
+            let game = GameMetadata {
+                namespace: row.get("namespace")?,
+                server: row.get("server")?,
+                player_count: row.get("player_count")?,
+                date_played: date_played.with_timezone(&Utc),
+                duration_seconds: row.get("duration_seconds")?,
+            };
+            games.insert(
+                game_id,
+                GameStatsResponse {
+                    game,
+                    players: HashMap::new(),
+                    global: HashMap::new(),
+                },
+            );
+        }
+
+        for row in query_logged(&mut handle, players_sql).await?.rows() {
+            let game_id: Uuid = row.get("game_id")?;
+            if let Some(game) = games.get_mut(&game_id) {
+                let player_id: Uuid = row.get("player_id")?;
+                let namespace: String = row.get("namespace")?;
+                let key: String = row.get("key")?;
+                let value: f64 = row.get("value")?;
+                game.players
+                    .entry(player_id)
+                    .or_insert_with(HashMap::new)
+                    .entry(namespace)
+                    .or_insert_with(HashMap::new)
+                    .insert(key, value);
+            }
+        }
+
+        for row in query_logged(&mut handle, global_sql).await?.rows() {
+            let game_id: Uuid = row.get("game_id")?;
+            if let Some(game) = games.get_mut(&game_id) {
+                let namespace: String = row.get("namespace")?;
+                let key: String = row.get("key")?;
+                let value: f64 = row.get("value")?;
+                game.global
+                    .entry(namespace)
+                    .or_insert_with(HashMap::new)
+                    .insert(key, value);
+            }
+        }
+
+        games.retain(|_, game| !game.players.is_empty() || !game.global.is_empty());
+        Ok(games)
+    }
+
+    async fn get_servers(&mut self) -> StatisticsDatabaseResult<Vec<String>> {
+        if let Some((time, servers)) = &self.servers_cache {
+            if time.elapsed() < SERVERS_CACHE_TTL {
+                return Ok(servers.clone());
+            }
+        }
+
+        let mut handle = self.pool.get_handle().await?;
+        let result = query_logged(
+            &mut handle,
+            "SELECT DISTINCT server FROM games ORDER BY server",
+        )
+        .await?;
+
+        let mut servers = Vec::new();
+        for row in result.rows() {
+            servers.push(row.get("server")?);
+        }
+
+        self.servers_cache = Some((Instant::now(), servers.clone()));
+        Ok(servers)
+    }
+
+    async fn prune_old_statistics(&self, retention_days: u32) -> StatisticsDatabaseResult<()> {
+        let mut handle = self.pool.get_handle().await?;
+
+        // Fix the cutoff up front so that every statement agrees on which games are old
+        let cutoff = format!(
+            "toDateTime({})",
+            Utc::now().timestamp() - retention_days as i64 * 24 * 60 * 60
+        );
+        let old_games = format!("SELECT game_id FROM games WHERE date_played < {}", cutoff);
+
+        let count_sql = format!("SELECT COUNT(*) AS total FROM ({})", old_games);
+        let count: u64 = match query_logged(&mut handle, count_sql).await?.rows().next() {
+            Some(row) => row.get("total")?,
+            None => 0,
+        };
+        if count == 0 {
+            return Ok(());
+        }
+
+        warn!(
+            "permanently deleting {} games older than {} days and their statistics",
+            count, retention_days
+        );
+
+        // Statistics go first, as they are found through the games that are about to be deleted
+        for table in ["player_statistics", "global_statistics"] {
+            let sql = format!(
+                "ALTER TABLE {} DELETE WHERE game_id IN ({}) SETTINGS mutations_sync = 1",
+                table, old_games
+            );
+            execute_logged(&mut handle, sql).await?;
+        }
+        let sql = format!(
+            "ALTER TABLE games DELETE WHERE date_played < {} SETTINGS mutations_sync = 1",
+            cutoff
+        );
+        execute_logged(&mut handle, sql).await?;
+
+        warn!("deleted {} old games, rebuilding leaderboards", count);
+        self.leaderboards.update_all_leaderboards().await
     }
 
+    async fn delete_player_stats(
+        &self,
+        player_id: &Uuid,
+    ) -> StatisticsDatabaseResult<DeletedPlayerStats> {
+        let mut handle = self.pool.get_handle().await?;
+
+        // This should be safe, as although a uuid is potentially-untrusted user input,
+        // they are strictly formed and so no escape characters can be used to break out
+        // of the sql string and manipulate the query.
+        let count_sql = format!(
+            "SELECT COUNT(*) AS total FROM player_statistics WHERE player_id = '{}'",
+            player_id
+        );
+        let statistics = match query_logged(&mut handle, count_sql).await?.rows().next() {
+            Some(row) => row.get("total")?,
+            None => 0,
+        };
+
+        // Wait for the mutation to finish so that later rebuilds no longer see the player
+        let delete_sql = format!(
+            "ALTER TABLE player_statistics DELETE WHERE player_id = '{}' SETTINGS mutations_sync = 1",
+            player_id
+        );
+        execute_logged(&mut handle, delete_sql).await?;
+
+        let rankings = self.leaderboards.delete_player(player_id).await?;
+
+        Ok(DeletedPlayerStats {
+            statistics,
+            rankings,
+        })
+    }
+
+    fn validate_stats_bundle(
+        &self,
+        server: &str,
+        bundle: &mut GameStatsBundle,
+    ) -> StatisticsDatabaseResult<()> {
+        if !is_valid_namespace(&bundle.namespace) {
+            return Err(StatisticsDatabaseError::InvalidNamespace(
+                bundle.namespace.clone(),
+            ));
+        }
+
+        if let Some(max_rows) = self.config.max_bundle_rows {
+            let rows = bundle.stats.row_count();
+            if rows > max_rows {
+                return Err(StatisticsDatabaseError::BundleTooLarge { rows, max_rows });
+            }
+        }
+
+        let removed = bundle.stats.remove_invalid_keys();
+        if removed > 0 {
+            warn!(
+                "skipping {} statistics with empty keys in bundle for {} from server '{}'",
+                removed, bundle.namespace, server
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes the bundle to the database, returning how much of it was ingested once invalid
+    /// statistics had been removed.
     async fn upload_stats_bundle(
         &self,
         game_id: Uuid,
         server: &str,
-        bundle: GameStatsBundle,
-    ) -> StatisticsDatabaseResult<Uuid> {
+        mut bundle: GameStatsBundle,
+    ) -> StatisticsDatabaseResult<IngestionCounts> {
+        self.validate_stats_bundle(server, &mut bundle)?;
+        let counts = IngestionCounts::of(&bundle.stats);
+
         let mut handle = self.pool.get_handle().await?;
 
         // Steps to insert a whole stats bundle
         {
-            let date_played = Utc::now().with_timezone(&Tz::GMT);
+            let date_played = Utc::now().with_timezone(&self.timezone);
 
             // 1. Insert a row into the games table and record the allocated ID
             let mut block = Block::with_capacity(1);
@@ -288,6 +677,7 @@ impl StatisticDatabaseController {
                 player_count: bundle.stats.players.len() as u32,
                 server: server,
                 date_played: date_played,
+                duration_seconds: bundle.duration_seconds,
             })?;
 
             handle.insert("games", block).await?;
@@ -332,26 +722,89 @@ impl StatisticDatabaseController {
         // a dirty flag and updating at fixed intervals in the future.
         self.leaderboards.update_all_leaderboards().await?;
 
-        Ok(game_id)
+        Ok(counts)
     }
 
-    async fn get_statistics_stats(&self) -> StatisticsDatabaseResult<StatisticsStats> {
+    /// Starts fetching the usernames of top-ranked players in the background, if enabled. If the
+    /// previous warmer is still running, it is left to finish rather than starting over, since
+    /// leaderboards can be rebuilt faster than it gets through them.
+    async fn warm_usernames(&mut self) {
+        let Some(config) = &self.config.username_warmer else {
+            return;
+        };
+        if let Some(warmer) = &self.username_warmer {
+            if !warmer.is_finished() {
+                return;
+            }
+        }
+        let spacing = Duration::from_millis(config.spacing_millis);
+
+        let players = match self.leaderboards.get_top_players(config.top_players).await {
+            Ok(players) => players,
+            Err(e) => {
+                warn!("failed to list top players to fetch usernames for: {}", e);
+                return;
+            }
+        };
+
+        let controller = self.controller.clone();
+        self.username_warmer = Some(tokio::spawn(warm_usernames(controller, players, spacing)));
+    }
+
+    fn record_ingestion(&mut self, namespace: String, counts: IngestionCounts) {
+        self.recent_uploads
+            .push_back((Instant::now(), namespace, counts));
+        self.prune_recent_uploads();
+    }
+
+    fn prune_recent_uploads(&mut self) {
+        while let Some((time, ..)) = self.recent_uploads.front() {
+            if time.elapsed() < INGESTION_WINDOW {
+                break;
+            }
+            self.recent_uploads.pop_front();
+        }
+    }
+
+    fn ingestion_rate(&mut self) -> IngestionRate {
+        self.prune_recent_uploads();
+
+        let mut rate = IngestionRate::default();
+        for (_, namespace, counts) in &self.recent_uploads {
+            rate.total.add(counts);
+            rate.namespaces
+                .entry(namespace.clone())
+                .or_default()
+                .add(counts);
+        }
+        rate
+    }
+
+    async fn get_statistics_stats(&mut self) -> StatisticsDatabaseResult<StatisticsStats> {
+        let ttl = Duration::from_secs(self.config.stats_cache_seconds);
+        if let Some((time, stats)) = &self.stats_cache {
+            if time.elapsed() < ttl {
+                return Ok(stats.clone());
+            }
+        }
+
         let mut handle = self.pool.get_handle().await?;
-        let player_results = handle
-            .query(
-                r#"
+        let player_results = query_logged(
+            &mut handle,
+            r#"
         SELECT COUNT(DISTINCT player_id) AS unique_players,
             COUNT(*) AS total_entries,
             SUM(value) AS grand_total
         FROM player_statistics"#,
-            )
-            .fetch_all()
-            .await?;
-        let game_results = handle
-            .query("SELECT COUNT(*) AS games_played FROM games")
-            .fetch_all()
-            .await?;
-        let global_results = handle.query("SELECT COUNT(*) AS total_entries, SUM(value) as grand_total FROM global_statistics").fetch_all().await?;
+        )
+        .await?;
+        let game_results =
+            query_logged(&mut handle, "SELECT COUNT(*) AS games_played FROM games").await?;
+        let global_results = query_logged(
+            &mut handle,
+            "SELECT COUNT(*) AS total_entries, SUM(value) as grand_total FROM global_statistics",
+        )
+        .await?;
 
         let unique_players = player_results.get(0, "unique_players")?;
         let games_played = game_results.get(0, "games_played")?;
@@ -361,7 +814,7 @@ impl StatisticDatabaseController {
         let player_total = player_results.get(0, "grand_total")?;
         let global_total = global_results.get(0, "grand_total")?;
         let grand_total = player_total + global_total;
-        Ok(StatisticsStats {
+        let stats = StatisticsStats {
             unique_players,
             games_played,
             entries: StatisticCounts {
@@ -374,78 +827,62 @@ impl StatisticDatabaseController {
                 global: global_total,
                 total: grand_total,
             },
-        })
+        };
+
+        self.stats_cache = Some((Instant::now(), stats.clone()));
+        Ok(stats)
     }
 
     async fn data_query(
         &self,
         query_type: DataQueryType,
-    ) -> StatisticsDatabaseResult<Vec<Datapoint>> {
+    ) -> StatisticsDatabaseResult<DataQueryResult> {
+        let (counted, bucket) = match query_type {
+            DataQueryType::GamesByDay => (DateCounted::Games, DateBucket::Day),
+            DataQueryType::GamesByMonth => (DateCounted::Games, DateBucket::Month),
+            DataQueryType::GamesByYear => (DateCounted::Games, DateBucket::Year),
+            DataQueryType::PlayersByDay => (DateCounted::Players, DateBucket::Day),
+            DataQueryType::PlayersByMonth => (DateCounted::Players, DateBucket::Month),
+            DataQueryType::PlayersByYear => (DateCounted::Players, DateBucket::Year),
+            // These aren't a single count per date bucket, so they don't fit the queries below
+            DataQueryType::PlayerCountHistogram => return self.player_count_histogram().await,
+            DataQueryType::UniquePlayersByMonth => return self.unique_players_by_month().await,
+        };
+
         let mut handle = self.pool.get_handle().await?;
-        let query = match query_type {
-            DataQueryType::GamesByDay => {
-                r#"
-            SELECT
-                DATE(date_played) AS date,
-                COUNT(*) AS value
-            FROM games
-            GROUP BY date
-            "#
-            }
-            DataQueryType::GamesByMonth => {
-                r#"
-            SELECT
-                toStartOfMonth(DATE(date_played)) AS date,
-                COUNT(*) AS value
-            FROM games
-            GROUP BY date
-            "#
-            }
-            DataQueryType::GamesByYear => {
+        // Bucket by calendar days in the configured timezone rather than the server's
+        let date = format!("toDate(games.date_played, '{}')", self.timezone.name());
+        let date = match bucket {
+            DateBucket::Day => date,
+            DateBucket::Month => format!("toStartOfMonth({})", date),
+            DateBucket::Year => format!("toStartOfYear({})", date),
+        };
+        let query = match counted {
+            DateCounted::Games => format!(
                 r#"
             SELECT
-                toStartOfYear(DATE(date_played)) AS date,
+                {date} AS date,
                 COUNT(*) AS value
             FROM games
             GROUP BY date
-            "#
-            }
-            DataQueryType::PlayersByDay => {
-                r#"
-            SELECT
-                DATE(games.date_played) AS date,
-                COUNT(DISTINCT player_statistics.player_id) as value
-            FROM player_statistics
-            LEFT JOIN games
-                ON player_statistics.game_id = games.game_id
-            GROUP BY date
-            "#
-            }
-            DataQueryType::PlayersByMonth => {
-                r#"
-            SELECT
-                toStartOfMonth(DATE(games.date_played)) AS date,
-                COUNT(DISTINCT player_statistics.player_id) as value
-            FROM player_statistics
-            LEFT JOIN games
-                ON player_statistics.game_id = games.game_id
-            GROUP BY date
-            "#
-            }
-            DataQueryType::PlayersByYear => {
+            "#,
+                date = date
+            ),
+            DateCounted::Players => format!(
                 r#"
             SELECT
-                toStartOfYear(DATE(games.date_played)) AS date,
+                {date} AS date,
                 COUNT(DISTINCT player_statistics.player_id) as value
             FROM player_statistics
             LEFT JOIN games
                 ON player_statistics.game_id = games.game_id
             GROUP BY date
-            "#
-            }
+            "#,
+                date = date
+            ),
         };
 
-        let result = handle.query(query).fetch_all().await?;
+        let result = query_logged(&mut handle, query).await?;
         let rows = result.rows();
         let mut data = Vec::new();
         for row in rows {
@@ -454,78 +891,319 @@ impl StatisticDatabaseController {
             data.push(Datapoint { date, value });
         }
 
-        Ok(data)
+        Ok(DataQueryResult::ByDate(data))
     }
 
-    async fn wrapped_data(&self, player_id: Uuid, year: u16) -> StatisticsDatabaseResult<PlayerWrappedData> {
-        let result = self.wrapped.build_wrapped(player_id, year).await?;
+    async fn player_count_histogram(&self) -> StatisticsDatabaseResult<DataQueryResult> {
+        let mut handle = self.pool.get_handle().await?;
+        let result = query_logged(
+            &mut handle,
+            r#"
+            SELECT
+                player_count,
+                COUNT(*) AS value
+            FROM games
+            GROUP BY player_count
+            ORDER BY player_count
+            "#,
+        )
+        .await?;
+
+        let mut data = Vec::new();
+        for row in result.rows() {
+            let player_count: u32 = row.get("player_count")?;
+            let value: u64 = row.get("value")?;
+            data.push(PlayerCountBucket {
+                player_count,
+                value,
+            });
+        }
+
+        Ok(DataQueryResult::ByPlayerCount(data))
+    }
+
+    async fn unique_players_by_month(&self) -> StatisticsDatabaseResult<DataQueryResult> {
+        let mut handle = self.pool.get_handle().await?;
+        let date = format!("toDate(games.date_played, '{}')", self.timezone.name());
+        let query = format!(
+            r#"
+            SELECT
+                toStartOfMonth(first_played) AS date,
+                COUNT(*) AS value
+            FROM
+                (SELECT
+                    player_statistics.player_id,
+                    MIN({date}) AS first_played
+                FROM player_statistics
+                INNER JOIN games
+                    ON player_statistics.game_id = games.game_id
+                GROUP BY player_statistics.player_id)
+            GROUP BY date
+            ORDER BY date
+            "#,
+            date = date
+        );
+
+        let result = query_logged(&mut handle, query).await?;
+        let mut total_players = 0;
+        let mut data = Vec::new();
+        for row in result.rows() {
+            let date: NaiveDate = row.get("date")?;
+            let new_players: u64 = row.get("value")?;
+            total_players += new_players;
+            data.push(UniquePlayersDatapoint {
+                date,
+                new_players,
+                total_players,
+            });
+        }
+
+        Ok(DataQueryResult::UniquePlayers(data))
+    }
+
+    async fn wrapped_data(
+        &self,
+        player_id: Uuid,
+        year: u16,
+        namespace: Option<&str>,
+    ) -> StatisticsDatabaseResult<PlayerWrappedData> {
+        let result = self
+            .wrapped
+            .build_wrapped(player_id, year, namespace)
+            .await?;
         Ok(result)
     }
 }
 
+/// What is counted for each date in a date-bucketed [`DataQueryType`].
+enum DateCounted {
+    Games,
+    Players,
+}
+
+/// The span of time covered by each date in a date-bucketed [`DataQueryType`].
+enum DateBucket {
+    Day,
+    Month,
+    Year,
+}
+
 pub struct GetPlayerStats {
     pub uuid: Uuid,
     pub namespace: Option<String>,
+    /// Include every key recorded in the namespace, with zero for those the player doesn't have.
+    pub all_keys: bool,
 }
 
 impl Handler<GetPlayerStats> for StatisticDatabaseController {
     type Return = StatisticsDatabaseResult<Option<PlayerStatsResponse>>;
 
     async fn handle(&mut self, message: GetPlayerStats, _ctx: &mut Context<Self>) -> Self::Return {
-        self.get_player_stats(&message.uuid, &message.namespace)
+        self.get_player_stats(&message.uuid, &message.namespace, message.all_keys)
             .await
     }
 }
 
-pub struct GetGameStats(pub Uuid);
+pub struct GetGameStats {
+    pub uuid: Uuid,
+    pub namespace: Option<String>,
+}
 
 impl Handler<GetGameStats> for StatisticDatabaseController {
-    type Return = StatisticsDatabaseResult<Option<HashMap<Uuid, PlayerStatsResponse>>>;
+    type Return = StatisticsDatabaseResult<Option<GameStatsResponse>>;
 
     async fn handle(&mut self, message: GetGameStats, _ctx: &mut Context<Self>) -> Self::Return {
-        self.get_game_stats(&message.0).await
+        self.get_game_stats(&message.uuid, &message.namespace).await
+    }
+}
+
+pub struct GetGamesStats(pub Vec<Uuid>);
+
+impl Handler<GetGamesStats> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<HashMap<Uuid, GameStatsResponse>>;
+
+    async fn handle(&mut self, message: GetGamesStats, _ctx: &mut Context<Self>) -> Self::Return {
+        self.get_games_stats(&message.0).await
     }
 }
 
 pub struct GetRecentGames {
     pub limit: u32,
     pub player_id: Option<Uuid>,
+    /// Only include games played at or after this time.
+    pub since: Option<DateTime<Utc>>,
 }
 
 impl Handler<GetRecentGames> for StatisticDatabaseController {
     type Return = StatisticsDatabaseResult<Vec<RecentGame>>;
 
     async fn handle(&mut self, message: GetRecentGames, _ctx: &mut Context<Self>) -> Self::Return {
-        self.get_recent_games(message.limit, message.player_id)
+        self.get_recent_games(message.limit, message.player_id, message.since)
             .await
     }
 }
 
+/// Fetches the username of each player through the Mojang API client, one at a time so as not to
+/// be rate limited.
+async fn warm_usernames(controller: Address<Controller>, players: Vec<Uuid>, spacing: Duration) {
+    let Ok(Some(mojang_client)) = controller.send(GetMojangApiClient).await else {
+        debug!("not fetching usernames of top players, as there is no Mojang API client");
+        return;
+    };
+
+    debug!("fetching usernames of {} top players", players.len());
+    for player in players {
+        match mojang_client.send(GetPlayerUsername(player, None)).await {
+            Ok(Err(ClientError::RateLimited { retry_after })) => {
+                let retry_after = Duration::from_secs(retry_after.unwrap_or(60));
+                tokio::time::sleep(retry_after).await;
+            }
+            Ok(Err(e)) => debug!("failed to fetch username of {}: {}", player, e),
+            Ok(Ok(_)) => {}
+            Err(_) => return,
+        }
+        tokio::time::sleep(spacing).await;
+    }
+}
+
 #[derive(Debug)]
 pub struct UploadStatsBundle {
     pub game_id: Uuid,
     pub server: String,
     pub bundle: GameStatsBundle,
+    /// Validate the bundle without writing it to the database or rebuilding leaderboards.
+    pub dry_run: bool,
 }
 
 impl Handler<UploadStatsBundle> for StatisticDatabaseController {
-    type Return = ();
+    /// The validated bundle that would have been written, if this was a dry run.
+    type Return = StatisticsDatabaseResult<Option<GameStatsBundle>>;
 
     async fn handle(
         &mut self,
         message: UploadStatsBundle,
         _ctx: &mut Context<Self>,
     ) -> Self::Return {
-        if let Err(e) = self
-            .upload_stats_bundle(
-                message.game_id,
-                &message.server.clone(),
-                message.bundle.clone(),
-            )
+        if message.dry_run {
+            let mut bundle = message.bundle;
+            return match self.validate_stats_bundle(&message.server, &mut bundle) {
+                Ok(()) => {
+                    info!(
+                        "dry run: validated stats bundle {} for {} from server '{}' without writing it",
+                        message.game_id, bundle.namespace, message.server
+                    );
+                    Ok(Some(bundle))
+                }
+                Err(e) => {
+                    warn!(
+                        "dry run: stats bundle {} from server '{}' failed validation: {}",
+                        message.game_id, message.server, e
+                    );
+                    Err(e)
+                }
+            };
+        }
+
+        let namespace = message.bundle.namespace.clone();
+        let counts = match self
+            .upload_stats_bundle(message.game_id, &message.server, message.bundle)
             .await
         {
-            warn!("Failed to upload stats bundle {:?}: {}", message, e);
-        }
+            Ok(counts) => counts,
+            Err(e) => {
+                error!(
+                    "Failed to upload stats bundle {} from server '{}': {}",
+                    message.game_id, message.server, e
+                );
+                return Err(e);
+            }
+        };
+
+        self.record_ingestion(namespace, counts);
+        self.warm_usernames().await;
+
+        Ok(None)
+    }
+}
+
+/// Permanently deletes games older than the retention period, along with their statistics.
+pub struct PruneOldStatistics {
+    pub retention_days: u32,
+}
+
+impl Handler<PruneOldStatistics> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<()>;
+
+    async fn handle(
+        &mut self,
+        message: PruneOldStatistics,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        self.prune_old_statistics(message.retention_days).await?;
+        self.warm_usernames().await;
+        Ok(())
+    }
+}
+
+/// Deletes all statistics recorded for a player and removes them from leaderboards. The remaining
+/// leaderboards are rebuilt in the background once the delete has finished.
+pub struct DeletePlayerStats(pub Uuid);
+
+impl Handler<DeletePlayerStats> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<DeletedPlayerStats>;
+
+    async fn handle(
+        &mut self,
+        message: DeletePlayerStats,
+        ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        let deleted = self.delete_player_stats(&message.0).await?;
+
+        // Rebuilding every leaderboard is slow, so queue it rather than holding up the caller
+        let address = ctx.mailbox().address().downgrade();
+        tokio::spawn(async move {
+            if let Ok(Err(e)) = address.send(RebuildLeaderboards).await {
+                warn!(
+                    "failed to rebuild leaderboards after deleting a player: {}",
+                    e
+                );
+            }
+        });
+
+        Ok(deleted)
+    }
+}
+
+/// Rebuilds every leaderboard from scratch.
+pub struct RebuildLeaderboards;
+
+impl Handler<RebuildLeaderboards> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<()>;
+
+    async fn handle(
+        &mut self,
+        _message: RebuildLeaderboards,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        info!("rebuilding all leaderboards");
+        self.leaderboards.update_all_leaderboards().await?;
+        self.warm_usernames().await;
+        Ok(())
+    }
+}
+
+/// Gets how much has been ingested within the last [`INGESTION_WINDOW`].
+pub struct GetIngestionRate;
+
+impl Handler<GetIngestionRate> for StatisticDatabaseController {
+    type Return = IngestionRate;
+
+    async fn handle(
+        &mut self,
+        _message: GetIngestionRate,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        self.ingestion_rate()
     }
 }
 
@@ -543,34 +1221,160 @@ impl Handler<GetStatisticsStats> for StatisticDatabaseController {
     }
 }
 
-pub struct GetLeaderboard(pub String);
+/// Lists every server that has ever uploaded a game.
+pub struct GetServers;
+
+impl Handler<GetServers> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<Vec<String>>;
+
+    async fn handle(&mut self, _message: GetServers, _ctx: &mut Context<Self>) -> Self::Return {
+        self.get_servers().await
+    }
+}
+
+pub struct GetLeaderboard {
+    pub id: String,
+    pub formatted: bool,
+    pub order: LeaderboardOrder,
+    /// Only count games played on this server.
+    pub server: Option<String>,
+}
 
 impl Handler<GetLeaderboard> for StatisticDatabaseController {
     type Return = StatisticsDatabaseResult<Option<Vec<LeaderboardEntry>>>;
 
     async fn handle(&mut self, message: GetLeaderboard, _ctx: &mut Context<Self>) -> Self::Return {
-        self.leaderboards.get_leaderboard(&message.0).await
+        match &message.server {
+            Some(server) => {
+                self.leaderboards
+                    .get_server_leaderboard(&message.id, server, message.formatted, message.order)
+                    .await
+            }
+            None => {
+                self.leaderboards
+                    .get_leaderboard(
+                        &message.id,
+                        message.formatted,
+                        message.order,
+                        DEFAULT_LEADERBOARD_LIMIT,
+                    )
+                    .await
+            }
+        }
+    }
+}
+
+pub struct GetLeaderboardWithPlayer {
+    pub id: String,
+    pub formatted: bool,
+    pub order: LeaderboardOrder,
+    pub player: Uuid,
+}
+
+impl Handler<GetLeaderboardWithPlayer> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<Option<LeaderboardWithPlayer>>;
+
+    async fn handle(
+        &mut self,
+        message: GetLeaderboardWithPlayer,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        let top = self
+            .leaderboards
+            .get_leaderboard(
+                &message.id,
+                message.formatted,
+                message.order,
+                DEFAULT_LEADERBOARD_LIMIT,
+            )
+            .await?;
+        let Some(top) = top else {
+            return Ok(None);
+        };
+
+        let player = self
+            .leaderboards
+            .get_player_ranking(&message.id, &message.player, message.formatted)
+            .await?;
+        Ok(Some(LeaderboardWithPlayer { top, player }))
     }
 }
 
-pub struct GetAllLeaderboards;
+/// Fetches the top of several leaderboards at once. Unknown or empty leaderboards map to `None`.
+pub struct GetLeaderboards {
+    pub ids: Vec<String>,
+    pub limit: i64,
+}
+
+impl Handler<GetLeaderboards> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<HashMap<String, Option<Vec<LeaderboardEntry>>>>;
+
+    async fn handle(&mut self, message: GetLeaderboards, _ctx: &mut Context<Self>) -> Self::Return {
+        let leaderboards = &self.leaderboards;
+        let limit = message.limit;
+        let results = future::try_join_all(message.ids.into_iter().map(|id| async move {
+            let entries = leaderboards
+                .get_leaderboard(&id, false, LeaderboardOrder::default(), limit)
+                .await?;
+            Ok::<_, StatisticsDatabaseError>((id, entries))
+        }))
+        .await?;
+        Ok(results.into_iter().collect())
+    }
+}
+
+/// Gets when a leaderboard's stored rankings were last rebuilt.
+pub struct GetLeaderboardUpdated(pub String);
+
+impl Handler<GetLeaderboardUpdated> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<Option<SystemTime>>;
+
+    async fn handle(
+        &mut self,
+        message: GetLeaderboardUpdated,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        self.leaderboards.get_last_updated(&message.0).await
+    }
+}
+
+pub struct CountLeaderboard(pub String);
+
+impl Handler<CountLeaderboard> for StatisticDatabaseController {
+    type Return = StatisticsDatabaseResult<Option<i64>>;
+
+    async fn handle(
+        &mut self,
+        message: CountLeaderboard,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Return {
+        self.leaderboards.count_leaderboard(&message.0).await
+    }
+}
+
+pub struct GetAllLeaderboards {
+    pub namespace: Option<String>,
+}
 
 impl Handler<GetAllLeaderboards> for StatisticDatabaseController {
     type Return = Vec<String>;
 
     async fn handle(
         &mut self,
-        _message: GetAllLeaderboards,
+        message: GetAllLeaderboards,
         _ctx: &mut Context<Self>,
     ) -> Self::Return {
-        self.leaderboards.list_all_leaderboards()
+        match message.namespace {
+            Some(namespace) => self.leaderboards.list_leaderboards_in_namespace(&namespace),
+            None => self.leaderboards.list_all_leaderboards(),
+        }
     }
 }
 
 pub struct GetPlayerRankings(pub Uuid);
 
 impl Handler<GetPlayerRankings> for StatisticDatabaseController {
-    type Return = StatisticsDatabaseResult<Option<HashMap<String, (i64, f64)>>>;
+    type Return = StatisticsDatabaseResult<Option<HashMap<String, (i64, f64, i64)>>>;
 
     async fn handle(
         &mut self,
@@ -584,7 +1388,7 @@ impl Handler<GetPlayerRankings> for StatisticDatabaseController {
 pub struct DataQuery(pub DataQueryType);
 
 impl Handler<DataQuery> for StatisticDatabaseController {
-    type Return = StatisticsDatabaseResult<Vec<Datapoint>>;
+    type Return = StatisticsDatabaseResult<DataQueryResult>;
 
     async fn handle(&mut self, message: DataQuery, _ctx: &mut Context<Self>) -> Self::Return {
         self.data_query(message.0).await
@@ -594,13 +1398,19 @@ impl Handler<DataQuery> for StatisticDatabaseController {
 pub struct WrappedData {
     pub player_id: Uuid,
     pub year: u16,
+    pub namespace: Option<String>,
 }
 
 impl Handler<WrappedData> for StatisticDatabaseController {
     type Return = StatisticsDatabaseResult<PlayerWrappedData>;
 
     async fn handle(&mut self, message: WrappedData, _ctx: &mut Context<Self>) -> Self::Return {
-        self.wrapped_data(message.player_id, message.year).await
+        self.wrapped_data(
+            message.player_id,
+            message.year,
+            message.namespace.as_deref(),
+        )
+        .await
     }
 }
 
@@ -612,6 +1422,19 @@ pub enum StatisticsDatabaseError {
     Postgres(#[from] tokio_postgres::Error),
     #[error("a database pool error occurred: {0}")]
     Pool(#[from] deadpool_postgres::PoolError),
+    #[error("invalid statistics namespace: '{0}'")]
+    InvalidNamespace(String),
+    #[error("statistics bundle has {rows} rows, more than the maximum of {max_rows}")]
+    BundleTooLarge { rows: usize, max_rows: usize },
+    #[error("leaderboard '{leaderboard}' expected column '{column}' to be {expected}: {error}")]
+    ValueTypeMismatch {
+        leaderboard: String,
+        expected: &'static str,
+        column: String,
+        error: String,
+    },
+    #[error("unknown timezone: '{0}'")]
+    InvalidTimezone(String),
     #[error("nucleoid wrapped is not available for this year")]
     UnWrappedYear,
     #[error("unknown error")]
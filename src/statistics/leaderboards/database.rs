@@ -1,11 +1,18 @@
 use clickhouse_rs::Pool;
-use futures::StreamExt;
-use nucleoid_leaderboards::model::LeaderboardDefinition;
+use futures::{stream, StreamExt};
 use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 use crate::statistics::database::StatisticsDatabaseResult;
-use crate::statistics::leaderboards::{LeaderboardEntry, LeaderboardGenerator, LeaderboardValue};
+use crate::statistics::leaderboards::{
+    format_value, LeaderboardEntry, LeaderboardGenerator, LeaderboardOrder, LeaderboardValue,
+    LoadedLeaderboard, PlayerRanking, Ranker, TieRanking,
+};
+
+/// How many entries are returned from a leaderboard when no limit is given.
+pub const DEFAULT_LEADERBOARD_LIMIT: i64 = 10;
 
 pub const CREATE_LEADERBOARDS_TABLE: &str = r#"
 CREATE TABLE IF NOT EXISTS leaderboard_rankings(
@@ -17,10 +24,29 @@ CREATE TABLE IF NOT EXISTS leaderboard_rankings(
 );
 "#;
 
+/// Rankings stored before game counts were recorded are left without one until rebuilt.
+pub const ADD_LEADERBOARDS_GAME_COUNT_COLUMN: &str = r#"
+ALTER TABLE leaderboard_rankings ADD COLUMN IF NOT EXISTS game_count bigint;
+"#;
+
+/// When each leaderboard's stored rankings were last rebuilt.
+pub const CREATE_LEADERBOARD_UPDATES_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS leaderboard_updates(
+    leaderboard_id text NOT NULL PRIMARY KEY,
+    last_updated timestamptz NOT NULL
+);
+"#;
+
 pub async fn setup_leaderboard_tables(
     client: &deadpool_postgres::Object,
 ) -> StatisticsDatabaseResult<()> {
     client.execute(CREATE_LEADERBOARDS_TABLE, &[]).await?;
+    client
+        .execute(ADD_LEADERBOARDS_GAME_COUNT_COLUMN, &[])
+        .await?;
+    client
+        .execute(CREATE_LEADERBOARD_UPDATES_TABLE, &[])
+        .await?;
 
     Ok(())
 }
@@ -29,13 +55,17 @@ pub struct LeaderboardsDatabase {
     postgres_pool: deadpool_postgres::Pool,
     clickhouse_pool: clickhouse_rs::Pool,
     generator: LeaderboardGenerator,
+    rebuild_concurrency: usize,
+    tie_ranking: TieRanking,
 }
 
 impl LeaderboardsDatabase {
     pub async fn new(
         postgres_pool: deadpool_postgres::Pool,
         clickhouse_pool: Pool,
-        leaderboards: Vec<LeaderboardDefinition>,
+        leaderboards: Vec<LoadedLeaderboard>,
+        rebuild_concurrency: usize,
+        tie_ranking: TieRanking,
     ) -> StatisticsDatabaseResult<Self> {
         let client = postgres_pool.get().await?;
         setup_leaderboard_tables(&client).await?;
@@ -44,63 +74,198 @@ impl LeaderboardsDatabase {
             postgres_pool,
             clickhouse_pool,
             generator: LeaderboardGenerator::new(leaderboards),
+            rebuild_concurrency: rebuild_concurrency.max(1),
+            tie_ranking,
         })
     }
 
     pub async fn update_all_leaderboards(&self) -> StatisticsDatabaseResult<()> {
+        self.update_leaderboards(self.generator.list_all_leaderboards())
+            .await
+    }
+
+    /// Rebuilds only the leaderboards that haven't been rebuilt within `max_age`, returning how
+    /// many were rebuilt.
+    pub async fn update_stale_leaderboards(
+        &self,
+        max_age: Duration,
+    ) -> StatisticsDatabaseResult<usize> {
         let client = self.postgres_pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT leaderboard_id, last_updated FROM leaderboard_updates",
+                &[],
+            )
+            .await?;
+        let last_updated = rows
+            .iter()
+            .map(|row| {
+                let id: String = row.get("leaderboard_id");
+                let last_updated: SystemTime = row.get("last_updated");
+                (id, last_updated)
+            })
+            .collect::<HashMap<_, _>>();
+        // Rebuilding takes its own connections, so don't hold onto this one meanwhile
+        drop(client);
+
+        let stale = self
+            .generator
+            .list_all_leaderboards()
+            .into_iter()
+            .filter(|id| {
+                last_updated
+                    .get(id)
+                    .and_then(|time| time.elapsed().ok())
+                    .is_none_or(|age| age > max_age)
+            })
+            .collect::<Vec<_>>();
+
+        let count = stale.len();
+        self.update_leaderboards(stale).await?;
+        Ok(count)
+    }
+
+    async fn update_leaderboards(&self, leaderboards: Vec<String>) -> StatisticsDatabaseResult<()> {
+        // Each rebuild holds its own ClickHouse handle and Postgres client, so the concurrency
+        // limit also bounds how many connections we take from each pool.
+        rebuild_concurrently(
+            leaderboards,
+            self.rebuild_concurrency,
+            |leaderboard| async move { self.update_leaderboard(&leaderboard).await },
+        )
+        .await
+    }
+
+    async fn update_leaderboard(&self, leaderboard: &str) -> StatisticsDatabaseResult<()> {
+        let mut client = self.postgres_pool.get().await?;
         let mut handle = self.clickhouse_pool.get_handle().await?;
+        // Readers never see a half-rebuilt board, with some stale rankings already removed
+        let client = client.transaction().await?;
         let statement = client
             .prepare_cached(
                 r#"
-        INSERT INTO leaderboard_rankings (player_id, leaderboard_id, ranking, value)
-        VALUES($1, $2, $3, $4)
+        INSERT INTO leaderboard_rankings (player_id, leaderboard_id, ranking, value, game_count)
+        VALUES($1, $2, $3, $4, $5)
         ON CONFLICT (player_id, leaderboard_id)
-        DO UPDATE SET ranking = $3, value = $4
+        DO UPDATE SET ranking = $3, value = $4, game_count = $5
         "#,
             )
             .await?;
 
-        for leaderboard in self.generator.list_all_leaderboards() {
-            let entries = self
-                .generator
-                .build_leaderboard(&mut handle, &leaderboard)
-                .await?;
-            if let Some(mut entries) = entries {
-                let mut rank = 1_i64;
-                while let Some(entry) = entries.next().await {
-                    let entry: LeaderboardValue = entry?;
-                    client
-                        .execute(
-                            &statement,
-                            &[&entry.player_id, &leaderboard, &rank, &entry.value],
-                        )
-                        .await?;
-                    rank += 1;
-                }
+        let entries = self
+            .generator
+            .build_leaderboard(&mut handle, leaderboard)
+            .await?;
+        if let Some(mut entries) = entries {
+            let mut players = Vec::new();
+            let mut ranker = Ranker::new(self.tie_ranking);
+            while let Some(entry) = entries.next().await {
+                let entry: LeaderboardValue = entry?;
+                let rank = ranker.rank(entry.value);
+                players.push(entry.player_id);
+                let game_count = entry.game_count.map(|count| count as i64);
+                client
+                    .execute(
+                        &statement,
+                        &[
+                            &entry.player_id,
+                            &leaderboard,
+                            &rank,
+                            &entry.value,
+                            &game_count,
+                        ],
+                    )
+                    .await?;
             }
+
+            // Players who are no longer on the board, e.g. because their games were pruned,
+            // would otherwise keep their old ranking forever
+            let statement = client
+                .prepare_cached(
+                    r#"
+        DELETE FROM leaderboard_rankings
+        WHERE leaderboard_id = $1 AND NOT (player_id = ANY($2))
+        "#,
+                )
+                .await?;
+            client
+                .execute(&statement, &[&leaderboard, &players])
+                .await?;
         }
 
+        // Recorded even if the leaderboard is empty, as it is still up-to-date
+        let statement = client
+            .prepare_cached(
+                r#"
+        INSERT INTO leaderboard_updates (leaderboard_id, last_updated)
+        VALUES($1, $2)
+        ON CONFLICT (leaderboard_id)
+        DO UPDATE SET last_updated = $2
+        "#,
+            )
+            .await?;
+        client
+            .execute(&statement, &[&leaderboard, &SystemTime::now()])
+            .await?;
+
+        client.commit().await?;
         Ok(())
     }
 
+    /// Lists every player ranked at `max_ranking` or better on any leaderboard.
+    pub async fn get_top_players(&self, max_ranking: i64) -> StatisticsDatabaseResult<Vec<Uuid>> {
+        let client = self.postgres_pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                r#"
+        SELECT DISTINCT player_id
+        FROM leaderboard_rankings
+        WHERE ranking <= $1
+        "#,
+            )
+            .await?;
+        let rows = client.query(&statement, &[&max_ranking]).await?;
+        Ok(rows.iter().map(|row| row.get("player_id")).collect())
+    }
+
+    pub async fn get_last_updated(&self, id: &str) -> StatisticsDatabaseResult<Option<SystemTime>> {
+        let client = self.postgres_pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                r#"
+        SELECT last_updated
+        FROM leaderboard_updates
+        WHERE leaderboard_id = $1
+        "#,
+            )
+            .await?;
+        let row = client.query_opt(&statement, &[&id]).await?;
+        Ok(row.map(|row| row.get::<_, SystemTime>("last_updated")))
+    }
+
     pub async fn get_leaderboard(
         &self,
         id: &str,
+        formatted: bool,
+        order: LeaderboardOrder,
+        limit: i64,
     ) -> StatisticsDatabaseResult<Option<Vec<LeaderboardEntry>>> {
+        let value_format = self.generator.value_format(id).filter(|_| formatted);
+
         let client = self.postgres_pool.get().await?;
         let statement = client
-            .prepare_cached(
+            .prepare_cached(&format!(
                 r#"
-        SELECT player_id, ranking, value
+        SELECT player_id, ranking, value, game_count
         FROM leaderboard_rankings
         WHERE leaderboard_id = $1
-        ORDER BY ranking ASC
+        ORDER BY ranking {}
         LIMIT $2
         "#,
-            )
+                order.as_sql()
+            ))
             .await?;
-        let res = client.query(&statement, &[&id, &10_i64]).await?;
+        let res = client.query(&statement, &[&id, &limit]).await?;
         let leaderboard = res
             .iter()
             .map(|row| {
@@ -111,6 +276,8 @@ impl LeaderboardsDatabase {
                     player,
                     ranking,
                     value,
+                    formatted_value: value_format.map(|format| format_value(value, format)),
+                    game_count: row.get("game_count"),
                 }
             })
             .collect::<Vec<_>>();
@@ -121,16 +288,121 @@ impl LeaderboardsDatabase {
         })
     }
 
-    pub async fn get_player_rankings(
+    pub async fn get_player_ranking(
         &self,
+        id: &str,
         player: &Uuid,
-    ) -> StatisticsDatabaseResult<Option<HashMap<String, (i64, f64)>>> {
+        formatted: bool,
+    ) -> StatisticsDatabaseResult<Option<PlayerRanking>> {
+        let value_format = self.generator.value_format(id).filter(|_| formatted);
+
         let client = self.postgres_pool.get().await?;
         let statement = client
             .prepare_cached(
                 r#"
-        SELECT leaderboard_id, ranking, value
+        SELECT ranking, value, game_count
         FROM leaderboard_rankings
+        WHERE leaderboard_id = $1 AND player_id = $2
+        "#,
+            )
+            .await?;
+        let row = client.query_opt(&statement, &[&id, player]).await?;
+        Ok(row.map(|row| {
+            let ranking = row.get::<_, i64>("ranking");
+            let value = row.get::<_, f64>("value");
+            PlayerRanking {
+                ranking,
+                value,
+                formatted_value: value_format.map(|format| format_value(value, format)),
+                game_count: row.get("game_count"),
+            }
+        }))
+    }
+
+    /// Computes a leaderboard on the fly from only the games played on the given server. Unlike
+    /// [`Self::get_leaderboard`] this isn't read from the stored rankings, which only cover the
+    /// whole network.
+    pub async fn get_server_leaderboard(
+        &self,
+        id: &str,
+        server: &str,
+        formatted: bool,
+        order: LeaderboardOrder,
+    ) -> StatisticsDatabaseResult<Option<Vec<LeaderboardEntry>>> {
+        let value_format = self.generator.value_format(id).filter(|_| formatted);
+
+        let mut handle = self.clickhouse_pool.get_handle().await?;
+        let entries = self
+            .generator
+            .build_server_leaderboard(
+                &mut handle,
+                id,
+                server,
+                self.tie_ranking,
+                order,
+                DEFAULT_LEADERBOARD_LIMIT,
+            )
+            .await?;
+        let mut entries = match entries {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+
+        let mut leaderboard = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let entry: LeaderboardValue = entry?;
+            leaderboard.push(LeaderboardEntry {
+                player: entry.player_id,
+                ranking: entry
+                    .ranking
+                    .expect("server leaderboards are ranked by their query"),
+                value: entry.value,
+                formatted_value: value_format.map(|format| format_value(entry.value, format)),
+                game_count: entry.game_count.map(|count| count as i64),
+            });
+        }
+
+        Ok(if leaderboard.is_empty() {
+            None
+        } else {
+            Some(leaderboard)
+        })
+    }
+
+    /// Returns how many players are ranked on the given leaderboard, or `None` if it is unknown
+    /// or has never been computed.
+    pub async fn count_leaderboard(&self, id: &str) -> StatisticsDatabaseResult<Option<i64>> {
+        let client = self.postgres_pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                r#"
+        SELECT COUNT(*) AS total
+        FROM leaderboard_rankings
+        WHERE leaderboard_id = $1
+        "#,
+            )
+            .await?;
+        let row = client.query_one(&statement, &[&id]).await?;
+        let total = row.get::<_, i64>("total");
+        Ok(if total == 0 { None } else { Some(total) })
+    }
+
+    /// Returns the player's `(ranking, value, total)` on every leaderboard they are ranked on,
+    /// where `total` is how many players are ranked on that board.
+    pub async fn get_player_rankings(
+        &self,
+        player: &Uuid,
+    ) -> StatisticsDatabaseResult<Option<HashMap<String, (i64, f64, i64)>>> {
+        let client = self.postgres_pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                r#"
+        SELECT leaderboard_id, ranking, value, (
+            SELECT COUNT(*)
+            FROM leaderboard_rankings AS board
+            WHERE board.leaderboard_id = player.leaderboard_id
+        ) AS total
+        FROM leaderboard_rankings AS player
         WHERE player_id = $1
         "#,
             )
@@ -141,7 +413,8 @@ impl LeaderboardsDatabase {
             let leaderboard_id = row.get::<_, String>("leaderboard_id");
             let ranking = row.get::<_, i64>("ranking");
             let value = row.get::<_, f64>("value");
-            rankings.insert(leaderboard_id, (ranking, value));
+            let total = row.get::<_, i64>("total");
+            rankings.insert(leaderboard_id, (ranking, value, total));
         }
 
         Ok(if rankings.is_empty() {
@@ -151,7 +424,75 @@ impl LeaderboardsDatabase {
         })
     }
 
+    /// Removes the player from every leaderboard, returning how many rankings were removed.
+    pub async fn delete_player(&self, player: &Uuid) -> StatisticsDatabaseResult<u64> {
+        let client = self.postgres_pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                r#"
+        DELETE FROM leaderboard_rankings
+        WHERE player_id = $1
+        "#,
+            )
+            .await?;
+        Ok(client.execute(&statement, &[player]).await?)
+    }
+
     pub fn list_all_leaderboards(&self) -> Vec<String> {
         self.generator.list_all_leaderboards()
     }
+
+    pub fn list_leaderboards_in_namespace(&self, namespace: &str) -> Vec<String> {
+        self.generator.list_leaderboards_in_namespace(namespace)
+    }
+}
+
+/// Runs `rebuild` for every leaderboard, with at most `concurrency` running at once. Every board is
+/// attempted even if some fail, and the first error is returned.
+async fn rebuild_concurrently<F, Fut>(
+    leaderboards: Vec<String>,
+    concurrency: usize,
+    rebuild: F,
+) -> StatisticsDatabaseResult<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = StatisticsDatabaseResult<()>>,
+{
+    let results = stream::iter(leaderboards)
+        .map(rebuild)
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rebuilds_boards_concurrently_up_to_the_limit() {
+        let running = &AtomicUsize::new(0);
+        let most_running = &AtomicUsize::new(0);
+        let leaderboards = (0..20).map(|i| format!("board_{}", i)).collect();
+
+        let start = Instant::now();
+        rebuild_concurrently(leaderboards, 4, move |_| async move {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most_running.fetch_max(now_running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(most_running.load(Ordering::SeqCst), 4);
+        // One at a time, the 20 boards would take at least 400ms
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
 }
@@ -1,30 +1,119 @@
 pub mod database;
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use futures::{Stream, StreamExt};
 use nucleoid_leaderboards::model::{
     Aggregate, LeaderboardDefinition, LeaderboardQuery, Ranking, ValueFormat, ValueType,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::statistics::database::StatisticsDatabaseResult;
+use crate::statistics::database::{
+    query_stream_logged, StatisticsDatabaseError, StatisticsDatabaseResult,
+};
+
+/// A leaderboard definition along with the file it was loaded from.
+pub struct LoadedLeaderboard {
+    pub path: PathBuf,
+    pub definition: LeaderboardFile,
+}
+
+/// The contents of a leaderboard definition file. Boards that nucleoid-leaderboards can't describe
+/// yet are defined with one of the backend's own queries instead.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum LeaderboardFile {
+    Shared(LeaderboardDefinition),
+    Local {
+        id: String,
+        query: LocalLeaderboardQuery,
+    },
+}
+
+impl LeaderboardFile {
+    pub fn id(&self) -> &str {
+        match self {
+            LeaderboardFile::Shared(definition) => &definition.id,
+            LeaderboardFile::Local { id, .. } => id,
+        }
+    }
+
+    fn into_query(self) -> BoardQuery {
+        match self {
+            LeaderboardFile::Shared(definition) => BoardQuery::Shared(definition.query),
+            LeaderboardFile::Local { query, .. } => BoardQuery::Local(query),
+        }
+    }
+}
+
+/// Leaderboard queries that only the backend supports so far.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LocalLeaderboardQuery {
+    /// Ranks players by the total of one statistic divided by the total of another, e.g. kills per
+    /// death. Players whose denominator totals zero are left off the board.
+    Ratio {
+        numerator: StatisticKey,
+        denominator: StatisticKey,
+        ranking: Ranking,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct StatisticKey {
+    pub namespace: String,
+    pub key: String,
+}
+
+enum BoardQuery {
+    Shared(LeaderboardQuery),
+    Local(LocalLeaderboardQuery),
+}
+
+/// Leaderboard ids appear in URL paths, so they are restricted to characters that never need
+/// escaping there.
+pub fn is_valid_leaderboard_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '~' | ':'))
+}
 
 pub struct LeaderboardGenerator {
-    definitions: HashMap<String, (LeaderboardDefinition, LeaderboardSql)>,
+    definitions: HashMap<String, (BoardQuery, LeaderboardSql)>,
 }
 
 impl LeaderboardGenerator {
-    pub fn new(definitions: Vec<LeaderboardDefinition>) -> Self {
+    pub fn new(leaderboards: Vec<LoadedLeaderboard>) -> Self {
         let mut definitions_map = HashMap::new();
+        let mut sources = HashMap::new();
+
+        for LoadedLeaderboard { path, definition } in leaderboards {
+            let id = definition.id().to_owned();
+            if !is_valid_leaderboard_id(&id) {
+                tracing::error!("Skipping leaderboard in {:?}: invalid id '{}'", path, id);
+                continue;
+            }
 
-        for definition in definitions {
-            if definitions_map.contains_key(&definition.id) {
-                tracing::warn!("Duplicate leaderboard definition for {}", definition.id);
+            match sources.entry(id.clone()) {
+                Entry::Occupied(first) => {
+                    tracing::error!(
+                        "Ignoring duplicate leaderboard definition for {} in {:?}, already defined in {:?}",
+                        id,
+                        path,
+                        first.get()
+                    );
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(path);
+                    let query = definition.into_query();
+                    let sql = build_sql(&query);
+                    definitions_map.insert(id, (query, sql));
+                }
             }
-            let sql = build_sql(&definition);
-            definitions_map.insert(definition.id.clone(), (definition, sql));
         }
 
         Self {
@@ -44,18 +133,108 @@ impl LeaderboardGenerator {
             None => return Ok(None),
         };
 
-        let stream = handle.query(&sql.sql).stream().map(move |row| {
+        Ok(Some(Self::run_sql(handle, id, sql)))
+    }
+
+    /// Builds a statistic leaderboard from only the games played on the given server. Boards
+    /// defined with raw SQL can't be scoped to a server, so `None` is returned for them.
+    ///
+    /// Players are ranked by ClickHouse, and only `limit` of them are returned from the end of the
+    /// board given by `order`, each with their `ranking` set.
+    pub async fn build_server_leaderboard<'a>(
+        &self,
+        handle: &'a mut clickhouse_rs::ClientHandle,
+        id: &str,
+        server: &str,
+        tie_ranking: TieRanking,
+        order: LeaderboardOrder,
+        limit: i64,
+    ) -> StatisticsDatabaseResult<
+        Option<impl Stream<Item = StatisticsDatabaseResult<LeaderboardValue>> + 'a>,
+    > {
+        let (sql, ranking) = match self.definitions.get(id).map(|(query, _)| query) {
+            Some(BoardQuery::Shared(LeaderboardQuery::Statistic {
+                namespace,
+                key,
+                aggregate,
+                ranking,
+                value_format,
+            })) => (
+                build_statistic_sql(
+                    namespace,
+                    key,
+                    aggregate,
+                    ranking,
+                    value_format,
+                    Some(server),
+                ),
+                ranking,
+            ),
+            Some(BoardQuery::Local(LocalLeaderboardQuery::Ratio {
+                numerator,
+                denominator,
+                ranking,
+            })) => (
+                build_ratio_sql(numerator, denominator, ranking, Some(server)),
+                ranking,
+            ),
+            _ => return Ok(None),
+        };
+        let sql = rank_and_limit_sql(sql, ranking, tie_ranking, order, limit);
+
+        Ok(Some(Self::run_sql(handle, id, sql)))
+    }
+
+    fn run_sql<'a>(
+        handle: &'a mut clickhouse_rs::ClientHandle,
+        id: &str,
+        sql: LeaderboardSql,
+    ) -> impl Stream<Item = StatisticsDatabaseResult<LeaderboardValue>> + 'a {
+        let id = id.to_owned();
+        query_stream_logged(handle, &sql.sql).map(move |row| {
             let row = row?;
             let player_id: Uuid = row.get(&*sql.player)?;
             let value = match sql.value_type {
-                ValueType::Int => row.get::<i64, _>(&*sql.value)? as f64,
-                ValueType::UInt => row.get::<u64, _>(&*sql.value)? as f64,
-                ValueType::Float => row.get::<f64, _>(&*sql.value)?,
+                ValueType::Int => row.get::<i64, _>(&*sql.value).map(|value| value as f64),
+                ValueType::UInt => row.get::<u64, _>(&*sql.value).map(|value| value as f64),
+                ValueType::Float => row.get::<f64, _>(&*sql.value),
             };
-            Ok(LeaderboardValue { player_id, value })
-        });
+            let value = value.map_err(|e| {
+                let error = StatisticsDatabaseError::ValueTypeMismatch {
+                    leaderboard: id.clone(),
+                    expected: value_type_name(&sql.value_type),
+                    column: sql.value.clone(),
+                    error: e.to_string(),
+                };
+                tracing::error!("{}", error);
+                error
+            })?;
+            let game_count = match &sql.game_count {
+                Some(column) => Some(row.get::<u64, _>(&**column)?),
+                None => None,
+            };
+            let ranking = match &sql.ranking {
+                Some(column) => Some(row.get::<u64, _>(&**column)? as i64),
+                None => None,
+            };
+            Ok(LeaderboardValue {
+                player_id,
+                value,
+                game_count,
+                ranking,
+            })
+        })
+    }
 
-        Ok(Some(stream))
+    /// Returns how values on the given leaderboard should be formatted for display.
+    /// Boards defined with raw SQL and ratio boards are treated as plain counts.
+    pub fn value_format(&self, id: &str) -> Option<&ValueFormat> {
+        self.definitions.get(id).map(|(query, _)| match query {
+            BoardQuery::Shared(LeaderboardQuery::Statistic { value_format, .. }) => value_format,
+            BoardQuery::Shared(LeaderboardQuery::Sql { .. }) | BoardQuery::Local(_) => {
+                &ValueFormat::Count
+            }
+        })
     }
 
     pub fn list_all_leaderboards(&self) -> Vec<String> {
@@ -64,12 +243,125 @@ impl LeaderboardGenerator {
             .map(Clone::clone)
             .collect::<Vec<_>>()
     }
+
+    /// Returns the statistics namespace targeted by the given leaderboard, if it is not defined
+    /// with raw SQL. Ratio boards only have one if both of their statistics share it.
+    pub fn namespace(&self, id: &str) -> Option<&str> {
+        self.definitions.get(id).and_then(|(query, _)| match query {
+            BoardQuery::Shared(LeaderboardQuery::Statistic { namespace, .. }) => {
+                Some(namespace.as_str())
+            }
+            BoardQuery::Shared(LeaderboardQuery::Sql { .. }) => None,
+            BoardQuery::Local(LocalLeaderboardQuery::Ratio {
+                numerator,
+                denominator,
+                ..
+            }) => {
+                if numerator.namespace == denominator.namespace {
+                    Some(numerator.namespace.as_str())
+                } else {
+                    None
+                }
+            }
+        })
+    }
+
+    /// Lists the leaderboards that belong to the given namespace, either because they target it
+    /// or because their id is prefixed with it. Boards defined with raw SQL are never included.
+    pub fn list_leaderboards_in_namespace(&self, namespace: &str) -> Vec<String> {
+        self.definitions
+            .keys()
+            .filter(|id| match self.namespace(id) {
+                Some(board_namespace) => {
+                    board_namespace == namespace || has_namespace_prefix(id, namespace)
+                }
+                None => false,
+            })
+            .map(Clone::clone)
+            .collect::<Vec<_>>()
+    }
 }
 
 #[derive(Serialize)]
 pub struct LeaderboardValue {
     player_id: Uuid,
     value: f64,
+    /// How many games contributed to the value, if the board can tell.
+    game_count: Option<u64>,
+    /// The player's ranking, if the query ranked the board itself.
+    ranking: Option<i64>,
+}
+
+/// The order in which stored rankings are returned, where ascending puts the best players first.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaderboardOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How players with equal values are ranked when leaderboards are rebuilt.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TieRanking {
+    /// Tied players share a rank and the following ranks are skipped, e.g. 1, 1, 3.
+    #[default]
+    Standard,
+    /// Tied players share a rank and no ranks are skipped, e.g. 1, 1, 2.
+    Dense,
+}
+
+/// Assigns rankings to leaderboard values, which must be given best first so that a tie is always
+/// with the previous value.
+pub struct Ranker {
+    tie_ranking: TieRanking,
+    rank: i64,
+    position: i64,
+    previous_value: Option<f64>,
+}
+
+impl Ranker {
+    pub fn new(tie_ranking: TieRanking) -> Self {
+        Self {
+            tie_ranking,
+            rank: 0,
+            position: 0,
+            previous_value: None,
+        }
+    }
+
+    /// Returns the ranking of the next value on the board.
+    pub fn rank(&mut self, value: f64) -> i64 {
+        self.position += 1;
+        if self.previous_value != Some(value) {
+            self.rank = match self.tie_ranking {
+                TieRanking::Standard => self.position,
+                TieRanking::Dense => self.rank + 1,
+            };
+            self.previous_value = Some(value);
+        }
+        self.rank
+    }
+}
+
+impl TieRanking {
+    /// The ClickHouse window function that ranks ties the same way as [`Ranker`].
+    fn window_function_sql(self) -> &'static str {
+        match self {
+            TieRanking::Standard => "rank()",
+            TieRanking::Dense => "dense_rank()",
+        }
+    }
+}
+
+impl LeaderboardOrder {
+    fn as_sql(self) -> &'static str {
+        match self {
+            LeaderboardOrder::Asc => "ASC",
+            LeaderboardOrder::Desc => "DESC",
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -77,53 +369,203 @@ pub struct LeaderboardEntry {
     player: Uuid,
     ranking: i64,
     value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formatted_value: Option<String>,
+    /// How many games contributed to the value. This is `None` for boards defined with raw SQL.
+    game_count: Option<i64>,
+}
+
+/// Where a single player ranks on a leaderboard.
+#[derive(Serialize)]
+pub struct PlayerRanking {
+    ranking: i64,
+    value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formatted_value: Option<String>,
+    game_count: Option<i64>,
+}
+
+/// The top of a leaderboard together with a player's ranking, which is `None` if they are unranked.
+#[derive(Serialize)]
+pub struct LeaderboardWithPlayer {
+    pub top: Vec<LeaderboardEntry>,
+    pub player: Option<PlayerRanking>,
 }
 
-fn build_sql(definition: &LeaderboardDefinition) -> LeaderboardSql {
-    match &definition.query {
-        LeaderboardQuery::Sql {
+/// Whether the id is `namespace` followed by a separator, e.g. `bedwars:wins` or `bedwars_wins`.
+fn has_namespace_prefix(id: &str, namespace: &str) -> bool {
+    match id.strip_prefix(namespace) {
+        Some(rest) => rest.starts_with(|c: char| !c.is_ascii_alphanumeric()),
+        None => false,
+    }
+}
+
+/// Formats a leaderboard value for display, e.g. `1h 23m 45s` for times or `12` for counts.
+///
+/// Times must already be in seconds: statistics are recorded in ticks, and the leaderboard SQL
+/// divides them by 20 before the value gets here. Fractions of a second are rounded.
+pub fn format_value(value: f64, format: &ValueFormat) -> String {
+    match format {
+        ValueFormat::Time => {
+            let total = value.max(0.0).round() as u64;
+            let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+            if hours > 0 {
+                format!("{}h {}m {}s", hours, minutes, seconds)
+            } else if minutes > 0 {
+                format!("{}m {}s", minutes, seconds)
+            } else {
+                format!("{}s", seconds)
+            }
+        }
+        ValueFormat::Count => {
+            if value.fract() == 0.0 {
+                format!("{}", value as i64)
+            } else {
+                format!("{:.2}", value)
+            }
+        }
+    }
+}
+
+fn build_sql(query: &BoardQuery) -> LeaderboardSql {
+    match query {
+        BoardQuery::Shared(LeaderboardQuery::Sql {
             query,
             player,
             value,
             value_type,
             ..
-        } => LeaderboardSql {
+        }) => LeaderboardSql {
             sql: query.clone(),
             player: player.clone(),
             value: value.clone(),
             value_type: value_type.clone(),
+            game_count: None,
+            ranking: None,
         },
-        LeaderboardQuery::Statistic {
+        BoardQuery::Shared(LeaderboardQuery::Statistic {
             namespace,
             key,
             aggregate,
             ranking,
             value_format,
-        } => LeaderboardSql {
-            // TODO: Sanitize SQL here?
-            sql: format!(
-                r#"
+        }) => build_statistic_sql(namespace, key, aggregate, ranking, value_format, None),
+        BoardQuery::Local(LocalLeaderboardQuery::Ratio {
+            numerator,
+            denominator,
+            ranking,
+        }) => build_ratio_sql(numerator, denominator, ranking, None),
+    }
+}
+
+fn build_statistic_sql(
+    namespace: &str,
+    key: &str,
+    aggregate: &Aggregate,
+    ranking: &Ranking,
+    value_format: &ValueFormat,
+    server: Option<&str>,
+) -> LeaderboardSql {
+    let (join, server_filter) = match server {
+        Some(server) => (
+            "INNER JOIN games ON player_statistics.game_id = games.game_id",
+            format!("AND games.server = '{}'", server),
+        ),
+        None => ("", String::new()),
+    };
+
+    LeaderboardSql {
+        // TODO: Sanitize SQL here?
+        sql: format!(
+            r#"
+                SELECT
+                    player_id,
+                    {aggregate}{convert} as value,
+                    COUNT(DISTINCT player_statistics.game_id) as game_count
+                FROM
+                    player_statistics
+                {join}
+                WHERE
+                    player_statistics.namespace = '{namespace}'
+                    AND key = '{key}'
+                    {server_filter}
+                GROUP BY
+                    player_id
+                ORDER BY value {ranking}
+                "#,
+            namespace = namespace,
+            key = key,
+            join = join,
+            server_filter = server_filter,
+            aggregate = aggregate_sql(aggregate),
+            convert = convert_sql(value_format),
+            ranking = ranking_sql(ranking),
+        ),
+        player: "player_id".to_string(),
+        value: "value".to_string(),
+        value_type: ValueType::Float,
+        game_count: Some("game_count".to_string()),
+        ranking: None,
+    }
+}
+
+fn build_ratio_sql(
+    numerator: &StatisticKey,
+    denominator: &StatisticKey,
+    ranking: &Ranking,
+    server: Option<&str>,
+) -> LeaderboardSql {
+    let (join, server_filter) = match server {
+        Some(server) => (
+            "INNER JOIN games ON player_statistics.game_id = games.game_id",
+            format!("AND games.server = '{}'", server),
+        ),
+        None => ("", String::new()),
+    };
+    let statistic_filter = |statistic: &StatisticKey| {
+        format!(
+            "player_statistics.namespace = '{}' AND key = '{}'",
+            statistic.namespace, statistic.key
+        )
+    };
+
+    LeaderboardSql {
+        // The ratio is null for players whose denominator is zero, and is only unwrapped once
+        // those players have been filtered out
+        sql: format!(
+            r#"
+                SELECT
+                    player_id,
+                    assumeNotNull(ratio) as value,
+                    game_count
+                FROM (
                     SELECT
-                        player_id, {aggregate}{convert} as value
+                        player_id,
+                        sumIf(value, {numerator}) / nullIf(sumIf(value, {denominator}), 0) as ratio,
+                        COUNT(DISTINCT player_statistics.game_id) as game_count
                     FROM
                         player_statistics
+                    {join}
                     WHERE
-                        namespace = '{namespace}'
-                        AND key = '{key}'
+                        (({numerator}) OR ({denominator}))
+                        {server_filter}
                     GROUP BY
                         player_id
-                    ORDER BY value {ranking}
-                    "#,
-                namespace = namespace,
-                key = key,
-                aggregate = aggregate_sql(aggregate),
-                convert = convert_sql(value_format),
-                ranking = ranking_sql(ranking),
-            ),
-            player: "player_id".to_string(),
-            value: "value".to_string(),
-            value_type: ValueType::Float,
-        },
+                )
+                WHERE ratio IS NOT NULL
+                ORDER BY value {ranking}
+                "#,
+            numerator = statistic_filter(numerator),
+            denominator = statistic_filter(denominator),
+            join = join,
+            server_filter = server_filter,
+            ranking = ranking_sql(ranking),
+        ),
+        player: "player_id".to_string(),
+        value: "value".to_string(),
+        value_type: ValueType::Float,
+        game_count: Some("game_count".to_string()),
+        ranking: None,
     }
 }
 
@@ -143,6 +585,37 @@ fn convert_sql(convert: &ValueFormat) -> &'static str {
     }
 }
 
+/// Ranks every player on a board within ClickHouse and keeps only `limit` of them from the end
+/// given by `order`, so that only the requested entries are sent back.
+fn rank_and_limit_sql(
+    sql: LeaderboardSql,
+    ranking: &Ranking,
+    tie_ranking: TieRanking,
+    order: LeaderboardOrder,
+    limit: i64,
+) -> LeaderboardSql {
+    LeaderboardSql {
+        sql: format!(
+            r#"
+                SELECT
+                    *,
+                    {rank} OVER (ORDER BY {value} {ranking}) as ranking
+                FROM ({board})
+                ORDER BY ranking {order}
+                LIMIT {limit}
+                "#,
+            rank = tie_ranking.window_function_sql(),
+            value = sql.value,
+            ranking = ranking_sql(ranking),
+            board = sql.sql,
+            order = order.as_sql(),
+            limit = limit,
+        ),
+        ranking: Some("ranking".to_string()),
+        ..sql
+    }
+}
+
 fn ranking_sql(ranking: &Ranking) -> &'static str {
     match ranking {
         Ranking::Lowest => "ASC",
@@ -150,10 +623,179 @@ fn ranking_sql(ranking: &Ranking) -> &'static str {
     }
 }
 
+/// The ClickHouse column type that values of the given type are read as.
+fn value_type_name(value_type: &ValueType) -> &'static str {
+    match value_type {
+        ValueType::Int => "Int64",
+        ValueType::UInt => "UInt64",
+        ValueType::Float => "Float64",
+    }
+}
+
 #[derive(Clone)]
 struct LeaderboardSql {
     sql: String,
     player: String,
     value: String,
     value_type: ValueType,
+    /// The column holding how many games each value is made up from, if there is one.
+    game_count: Option<String>,
+    /// The column holding each player's ranking, if the query ranks the board itself.
+    ranking: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistic(namespace: &str, key: &str) -> StatisticKey {
+        StatisticKey {
+            namespace: namespace.to_owned(),
+            key: key.to_owned(),
+        }
+    }
+
+    fn board(path: &str, id: &str, namespace: &str) -> LoadedLeaderboard {
+        LoadedLeaderboard {
+            path: PathBuf::from(path),
+            definition: LeaderboardFile::Local {
+                id: id.to_owned(),
+                query: LocalLeaderboardQuery::Ratio {
+                    numerator: statistic(namespace, "kills"),
+                    denominator: statistic(namespace, "deaths"),
+                    ranking: Ranking::Highest,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn accepts_ids_that_are_safe_in_paths() {
+        assert!(is_valid_leaderboard_id("bedwars:wins"));
+        assert!(is_valid_leaderboard_id("bedwars_wins-total.v2~old"));
+    }
+
+    #[test]
+    fn rejects_ids_that_would_need_escaping() {
+        assert!(!is_valid_leaderboard_id(""));
+        assert!(!is_valid_leaderboard_id("bedwars wins"));
+        assert!(!is_valid_leaderboard_id("bedwars/wins"));
+        assert!(!is_valid_leaderboard_id("bedwars?wins"));
+        assert!(!is_valid_leaderboard_id("bedwars#wins"));
+        assert!(!is_valid_leaderboard_id("bédwars"));
+    }
+
+    #[test]
+    fn skips_boards_with_invalid_ids() {
+        let generator = LeaderboardGenerator::new(vec![
+            board("a.json", "bedwars:kdr", "bedwars"),
+            board("b.json", "bedwars kdr", "bedwars"),
+        ]);
+        assert_eq!(generator.list_all_leaderboards(), vec!["bedwars:kdr"]);
+    }
+
+    #[test]
+    fn keeps_the_first_of_duplicate_boards() {
+        let generator = LeaderboardGenerator::new(vec![
+            board("a.json", "kdr", "bedwars"),
+            board("b.json", "kdr", "skywars"),
+        ]);
+        assert_eq!(generator.list_all_leaderboards(), vec!["kdr"]);
+        assert_eq!(generator.namespace("kdr"), Some("bedwars"));
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_value(0.0, &ValueFormat::Time), "0s");
+        assert_eq!(format_value(42.0, &ValueFormat::Time), "42s");
+        assert_eq!(format_value(125.0, &ValueFormat::Time), "2m 5s");
+        assert_eq!(format_value(3600.0, &ValueFormat::Time), "1h 0m 0s");
+        assert_eq!(format_value(5025.0, &ValueFormat::Time), "1h 23m 45s");
+    }
+
+    #[test]
+    fn rounds_fractional_times() {
+        assert_eq!(format_value(59.4, &ValueFormat::Time), "59s");
+        assert_eq!(format_value(59.5, &ValueFormat::Time), "1m 0s");
+        assert_eq!(format_value(-3.0, &ValueFormat::Time), "0s");
+    }
+
+    #[test]
+    fn formats_counts() {
+        assert_eq!(format_value(0.0, &ValueFormat::Count), "0");
+        assert_eq!(format_value(12.0, &ValueFormat::Count), "12");
+        assert_eq!(format_value(1.5, &ValueFormat::Count), "1.50");
+        assert_eq!(format_value(2.0 / 3.0, &ValueFormat::Count), "0.67");
+    }
+
+    fn rank_all(tie_ranking: TieRanking, values: &[f64]) -> Vec<i64> {
+        let mut ranker = Ranker::new(tie_ranking);
+        values.iter().map(|&value| ranker.rank(value)).collect()
+    }
+
+    #[test]
+    fn standard_ranking_skips_ranks_after_ties() {
+        assert_eq!(
+            rank_all(TieRanking::Standard, &[10.0, 8.0, 8.0, 8.0, 5.0, 3.0, 3.0]),
+            vec![1, 2, 2, 2, 5, 6, 6]
+        );
+    }
+
+    #[test]
+    fn dense_ranking_does_not_skip_ranks_after_ties() {
+        assert_eq!(
+            rank_all(TieRanking::Dense, &[10.0, 8.0, 8.0, 8.0, 5.0, 3.0, 3.0]),
+            vec![1, 2, 2, 2, 3, 4, 4]
+        );
+    }
+
+    #[test]
+    fn ties_at_the_top_share_first_place() {
+        assert_eq!(
+            rank_all(TieRanking::Standard, &[7.0, 7.0, 1.0]),
+            vec![1, 1, 3]
+        );
+        assert_eq!(rank_all(TieRanking::Dense, &[7.0, 7.0, 1.0]), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn ratio_boards_divide_totals_and_skip_zero_denominators() {
+        let query = BoardQuery::Local(LocalLeaderboardQuery::Ratio {
+            numerator: statistic("bedwars", "kills"),
+            denominator: statistic("bedwars", "deaths"),
+            ranking: Ranking::Highest,
+        });
+
+        let sql = build_sql(&query).sql;
+        assert!(sql.contains(
+            "sumIf(value, player_statistics.namespace = 'bedwars' AND key = 'kills') / nullIf(sumIf(value, player_statistics.namespace = 'bedwars' AND key = 'deaths'), 0)"
+        ));
+        assert!(sql.contains("WHERE ratio IS NOT NULL"));
+        assert!(sql.contains("ORDER BY value DESC"));
+    }
+
+    #[test]
+    fn server_boards_are_ranked_and_limited_in_the_query() {
+        let sql = build_ratio_sql(
+            &statistic("bedwars", "kills"),
+            &statistic("bedwars", "deaths"),
+            &Ranking::Highest,
+            Some("survival"),
+        );
+        let sql = rank_and_limit_sql(
+            sql,
+            &Ranking::Highest,
+            TieRanking::Dense,
+            LeaderboardOrder::Desc,
+            10,
+        );
+
+        assert!(sql
+            .sql
+            .contains("dense_rank() OVER (ORDER BY value DESC) as ranking"));
+        assert!(sql.sql.contains("AND games.server = 'survival'"));
+        assert!(sql.sql.contains("ORDER BY ranking DESC"));
+        assert!(sql.sql.contains("LIMIT 10"));
+        assert_eq!(sql.ranking.as_deref(), Some("ranking"));
+    }
 }
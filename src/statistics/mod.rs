@@ -1,46 +1,149 @@
+use std::collections::HashMap;
 use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use nucleoid_leaderboards::model::LeaderboardDefinition;
 use walkdir::WalkDir;
 use xtra::{Address, Mailbox};
 
-use crate::statistics::database::StatisticDatabaseController;
-use crate::{Controller, RegisterStatisticsDatabaseController, StatisticsConfig};
+use crate::model::ErrorSeverity;
+use crate::statistics::database::{
+    GetIngestionRate, PruneOldStatistics, StatisticDatabaseController, INGESTION_WINDOW,
+};
+use crate::statistics::leaderboards::{LeaderboardFile, LoadedLeaderboard};
+use crate::statistics::model::IngestionRate;
+use crate::{BackendError, Controller, RegisterStatisticsDatabaseController, StatisticsConfig};
 
 pub mod database;
 pub mod leaderboards;
 pub mod model;
 mod wrapped;
 
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Discord allows at most 25 fields in an embed.
+const MAX_REPORTED_LEADERBOARD_ERRORS: usize = 25;
+
 pub async fn run(
     controller: Address<Controller>,
     config: StatisticsConfig,
     postgres_pool: deadpool_postgres::Pool,
 ) {
-    let statistics_database = StatisticDatabaseController::connect(
-        &controller,
-        postgres_pool,
-        &config,
-        load_leaderboards(&config),
-    )
-    .await
-    .expect("failed to connect to statistics database");
+    let (leaderboards, leaderboard_errors) = load_leaderboards(&config);
+
+    let statistics_database =
+        StatisticDatabaseController::connect(&controller, postgres_pool, &config, leaderboards)
+            .await
+            .expect("failed to connect to statistics database");
 
     let statistics_database = xtra::spawn_tokio(statistics_database, Mailbox::unbounded());
 
+    if let Some(retention_days) = config.retention_days {
+        tracing::warn!(
+            "statistics retention is enabled: games older than {} days will be permanently deleted",
+            retention_days
+        );
+
+        let statistics_weak = statistics_database.downgrade();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match statistics_weak
+                    .send(PruneOldStatistics { retention_days })
+                    .await
+                {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => tracing::error!("failed to prune old statistics: {}", err),
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
+    let statistics_weak = statistics_database.downgrade();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INGESTION_WINDOW);
+        loop {
+            interval.tick().await;
+            let Ok(rate) = statistics_weak.send(GetIngestionRate).await else {
+                break;
+            };
+            if rate.total.bundles > 0 {
+                log_ingestion_rate(&rate);
+            }
+        }
+    });
+
     controller
         .send(RegisterStatisticsDatabaseController {
             controller: statistics_database,
         })
         .await
         .expect("controller disconnected");
+
+    if !leaderboard_errors.is_empty() {
+        report_leaderboard_errors(&controller, leaderboard_errors).await;
+    }
+}
+
+fn log_ingestion_rate(rate: &IngestionRate) {
+    let mut namespaces = rate.namespaces.iter().collect::<Vec<_>>();
+    namespaces.sort_by(|a, b| b.1.bundles.cmp(&a.1.bundles).then(a.0.cmp(b.0)));
+    let namespaces = namespaces
+        .into_iter()
+        .map(|(namespace, counts)| format!("{}: {}", namespace, counts.bundles))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    tracing::info!(
+        "ingested {} stats bundles with {} players, {} player and {} global statistics in the last minute ({})",
+        rate.total.bundles,
+        rate.total.players,
+        rate.total.player_entries,
+        rate.total.global_entries,
+        namespaces
+    );
 }
 
-fn load_leaderboards(config: &StatisticsConfig) -> Vec<LeaderboardDefinition> {
+/// Reports leaderboard files that failed to load, since the boards they define silently won't
+/// exist otherwise.
+async fn report_leaderboard_errors(
+    controller: &Address<Controller>,
+    errors: Vec<(PathBuf, String)>,
+) {
+    let description = format!(
+        "{} leaderboard definition files failed to load, so their leaderboards are unavailable.",
+        errors.len()
+    );
+    let fields = errors
+        .into_iter()
+        .take(MAX_REPORTED_LEADERBOARD_ERRORS)
+        .map(|(path, error)| (path.display().to_string(), error))
+        .collect::<HashMap<_, _>>();
+
+    let _ = controller
+        .send(BackendError {
+            severity: ErrorSeverity::Warning,
+            title: "Failed to load leaderboards".to_owned(),
+            description,
+            fields: Some(fields),
+        })
+        .await;
+}
+
+/// Loads every leaderboard definition file, also returning the files that couldn't be loaded and
+/// why.
+fn load_leaderboards(
+    config: &StatisticsConfig,
+) -> (Vec<LoadedLeaderboard>, Vec<(PathBuf, String)>) {
     let mut leaderboards = Vec::new();
+    let mut errors = Vec::new();
 
     if let Some(leaderboards_dir) = &config.leaderboards_dir {
+        // Sorted so that which of two duplicate definitions wins doesn't depend on the filesystem
         for entry in WalkDir::new(leaderboards_dir)
+            .sort_by_file_name()
             .into_iter()
             .filter_map(|e| e.ok())
         {
@@ -50,16 +153,23 @@ fn load_leaderboards(config: &StatisticsConfig) -> Vec<LeaderboardDefinition> {
                     Ok(f) => f,
                     Err(e) => {
                         tracing::error!("Failed to open {:?}: {}", entry.path(), e);
+                        errors.push((entry.path().to_owned(), e.to_string()));
                         continue;
                     }
                 };
-                match serde_json::from_reader::<_, LeaderboardDefinition>(&file) {
-                    Ok(definition) => leaderboards.push(definition),
-                    Err(e) => tracing::error!("Failed to parse {:?}: {}", entry.path(), e),
+                match serde_json::from_reader::<_, LeaderboardFile>(&file) {
+                    Ok(definition) => leaderboards.push(LoadedLeaderboard {
+                        path: entry.path().to_owned(),
+                        definition,
+                    }),
+                    Err(e) => {
+                        tracing::error!("Failed to parse {:?}: {}", entry.path(), e);
+                        errors.push((entry.path().to_owned(), e.to_string()));
+                    }
                 }
             }
         }
     }
 
-    leaderboards
+    (leaderboards, errors)
 }
@@ -7,16 +7,24 @@ use clickhouse_rs::Pool;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::database::execute_logged;
+
 pub const CREATE_GAMES_TABLE: &str = r#"
 CREATE TABLE IF NOT EXISTS games(
     game_id         UUID DEFAULT generateUUIDv4(),
     namespace       String,
     player_count    UInt32,
     server          String,
-    date_played     DateTime
+    date_played     DateTime,
+    duration_seconds Nullable(UInt32)
 ) Engine=MergeTree() PRIMARY KEY game_id
 "#;
 
+/// Brings tables created before the `duration_seconds` column existed up to date.
+pub const ADD_GAMES_DURATION_COLUMN: &str = r#"
+ALTER TABLE games ADD COLUMN IF NOT EXISTS duration_seconds Nullable(UInt32)
+"#;
+
 pub const CREATE_PLAYER_STATS_TABLE: &str = r#"
 CREATE TABLE IF NOT EXISTS player_statistics(
     statistic_id    UUID DEFAULT generateUUIDv4(),
@@ -46,19 +54,52 @@ pub async fn initialise_database(db: &Pool) -> Result<(), clickhouse_rs::errors:
     // See if we can connect
     client.ping().await?;
 
-    client.execute(CREATE_GAMES_TABLE).await?;
-    client.execute(CREATE_PLAYER_STATS_TABLE).await?;
-    client.execute(CREATE_GLOBAL_STATS_TABLE).await?;
+    execute_logged(&mut client, CREATE_GAMES_TABLE).await?;
+    execute_logged(&mut client, ADD_GAMES_DURATION_COLUMN).await?;
+    execute_logged(&mut client, CREATE_PLAYER_STATS_TABLE).await?;
+    execute_logged(&mut client, CREATE_GLOBAL_STATS_TABLE).await?;
     Ok(())
 }
 
 pub type PlayerStatsResponse = HashMap<String, HashMap<String, f64>>;
 pub type PlayerStatsBundle = HashMap<Uuid, HashMap<String, UploadStat>>;
 
+#[derive(Serialize)]
+pub struct GameStatsResponse {
+    pub game: GameMetadata,
+    pub players: HashMap<Uuid, PlayerStatsResponse>,
+    pub global: PlayerStatsResponse,
+}
+
+/// The row recorded in the `games` table for a single game.
+#[derive(Serialize, Clone, Debug)]
+pub struct GameMetadata {
+    pub namespace: String,
+    pub server: String,
+    pub player_count: u32,
+    pub date_played: DateTime<Utc>,
+    pub duration_seconds: Option<u32>,
+}
+
+impl GameStatsResponse {
+    /// Flattens into the original response format, where global statistics are stored under the
+    /// nil UUID alongside the real players.
+    pub fn into_legacy(self) -> HashMap<Uuid, PlayerStatsResponse> {
+        let mut players = self.players;
+        if !self.global.is_empty() {
+            players.insert(Uuid::nil(), self.global);
+        }
+        players
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GameStatsBundle {
     pub namespace: String,
     pub stats: StatsBundle,
+    /// How long the game lasted, if the server reports it.
+    #[serde(default)]
+    pub duration_seconds: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -67,6 +108,62 @@ pub struct StatsBundle {
     pub players: PlayerStatsBundle,
 }
 
+impl StatsBundle {
+    /// Removes all statistics with invalid keys from this bundle, returning how many were removed.
+    pub fn remove_invalid_keys(&mut self) -> usize {
+        let mut removed = 0;
+        let mut retain_valid = |stats: &mut HashMap<String, UploadStat>| {
+            let len = stats.len();
+            stats.retain(|key, _| is_valid_key(key));
+            removed += len - stats.len();
+        };
+
+        if let Some(global) = &mut self.global {
+            retain_valid(global);
+        }
+        self.players.values_mut().for_each(retain_valid);
+
+        removed
+    }
+
+    /// The number of rows this bundle would insert across the player and global statistics tables.
+    pub fn row_count(&self) -> usize {
+        let global = self.global.as_ref().map_or(0, HashMap::len);
+        let players = self.players.values().map(HashMap::len).sum::<usize>();
+        global + players
+    }
+}
+
+/// Namespaces are used directly in queries, so they are restricted to alphanumeric characters and
+/// underscores.
+pub fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Server names are used directly in queries, so they are restricted to alphanumeric characters,
+/// underscores, dashes and dots.
+pub fn is_valid_server(server: &str) -> bool {
+    !server.is_empty()
+        && server
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+}
+
+/// How many rows were removed when deleting a player's statistics.
+#[derive(Serialize, Debug)]
+pub struct DeletedPlayerStats {
+    /// The number of statistic rows the player had, counted just before the delete was issued.
+    pub statistics: u64,
+    pub rankings: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RecentGame {
     pub id: Uuid,
@@ -74,6 +171,7 @@ pub struct RecentGame {
     pub players: Vec<Uuid>,
     pub server: String,
     pub date_played: DateTime<Utc>,
+    pub duration_seconds: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -121,7 +219,7 @@ impl From<UploadStat> for f64 {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct StatisticsStats {
     pub unique_players: u64,
     pub games_played: u64,
@@ -129,19 +227,82 @@ pub struct StatisticsStats {
     pub grand_total: StatisticCounts<f64>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct StatisticCounts<T> {
     pub player: T,
     pub global: T,
     pub total: T,
 }
 
+/// How much was uploaded in some number of stats bundles.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct IngestionCounts {
+    pub bundles: u64,
+    pub players: u64,
+    pub player_entries: u64,
+    pub global_entries: u64,
+}
+
+impl IngestionCounts {
+    pub fn of(bundle: &StatsBundle) -> Self {
+        IngestionCounts {
+            bundles: 1,
+            players: bundle.players.len() as u64,
+            player_entries: bundle.players.values().map(|s| s.len() as u64).sum(),
+            global_entries: bundle.global.as_ref().map_or(0, |s| s.len() as u64),
+        }
+    }
+
+    pub fn add(&mut self, other: &IngestionCounts) {
+        self.bundles += other.bundles;
+        self.players += other.players;
+        self.player_entries += other.player_entries;
+        self.global_entries += other.global_entries;
+    }
+}
+
+/// Everything ingested over the last minute, in total and by namespace.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct IngestionRate {
+    pub total: IngestionCounts,
+    pub namespaces: HashMap<String, IngestionCounts>,
+}
+
+/// A single bucket of a data query. `date` is the first day of the bucket (day, month or year),
+/// and is serialized as a plain `YYYY-MM-DD` date without any timezone information.
 #[derive(Serialize)]
 pub struct Datapoint {
     pub date: NaiveDate,
     pub value: u64,
 }
 
+/// How many games were played with a given number of players.
+#[derive(Serialize)]
+pub struct PlayerCountBucket {
+    pub player_count: u32,
+    pub value: u64,
+}
+
+/// How many players first played in a month, and how many had played by the end of it.
+#[derive(Serialize)]
+pub struct UniquePlayersDatapoint {
+    pub date: NaiveDate,
+    /// Players whose first game was in this month.
+    pub new_players: u64,
+    /// Players whose first game was in or before this month.
+    pub total_players: u64,
+}
+
+/// The result of a data query. Most queries are bucketed by date, but some have a different
+/// x-axis or more than one value per point; all serialize as a plain list of points.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum DataQueryResult {
+    ByDate(Vec<Datapoint>),
+    ByPlayerCount(Vec<PlayerCountBucket>),
+    UniquePlayers(Vec<UniquePlayersDatapoint>),
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DataQueryType {
@@ -151,4 +312,114 @@ pub enum DataQueryType {
     PlayersByDay,
     PlayersByMonth,
     PlayersByYear,
+    PlayerCountHistogram,
+    /// Both the new and the cumulative number of unique players for each month.
+    UniquePlayersByMonth,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_statistics_with_empty_keys() {
+        let player = Uuid::from_u128(1);
+        let mut bundle = StatsBundle {
+            global: Some(HashMap::from([
+                ("".to_owned(), UploadStat::IntTotal(1)),
+                ("rounds".to_owned(), UploadStat::IntTotal(3)),
+            ])),
+            players: HashMap::from([(
+                player,
+                HashMap::from([
+                    ("".to_owned(), UploadStat::IntTotal(2)),
+                    ("kills".to_owned(), UploadStat::IntTotal(5)),
+                    ("deaths".to_owned(), UploadStat::IntTotal(4)),
+                ]),
+            )]),
+        };
+
+        assert_eq!(bundle.remove_invalid_keys(), 2);
+        assert_eq!(bundle.row_count(), 3);
+        assert!(bundle.global.as_ref().unwrap().contains_key("rounds"));
+        let mut keys = bundle.players[&player].keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["deaths", "kills"]);
+    }
+
+    #[test]
+    fn serializes_datapoint_dates_without_a_time() {
+        let datapoint = Datapoint {
+            date: NaiveDate::from_ymd_opt(2024, 3, 7).unwrap(),
+            value: 12,
+        };
+        assert_eq!(
+            serde_json::to_string(&datapoint).unwrap(),
+            r#"{"date":"2024-03-07","value":12}"#
+        );
+    }
+
+    #[test]
+    fn rejects_empty_namespaces() {
+        assert!(!is_valid_namespace(""));
+        assert!(is_valid_namespace("bedwars"));
+    }
+
+    fn game_stats(global: PlayerStatsResponse) -> GameStatsResponse {
+        let stats = HashMap::from([(
+            "bedwars".to_owned(),
+            HashMap::from([("kills".to_owned(), 5.0)]),
+        )]);
+        GameStatsResponse {
+            game: GameMetadata {
+                namespace: "bedwars".to_owned(),
+                server: "play".to_owned(),
+                player_count: 1,
+                date_played: DateTime::from_timestamp(0, 0).unwrap(),
+                duration_seconds: None,
+            },
+            players: HashMap::from([(Uuid::from_u128(1), stats)]),
+            global,
+        }
+    }
+
+    #[test]
+    fn keeps_global_stats_out_of_players() {
+        let global = HashMap::from([(
+            "bedwars".to_owned(),
+            HashMap::from([("rounds".to_owned(), 3.0)]),
+        )]);
+        let json = serde_json::to_value(game_stats(global)).unwrap();
+
+        let players = json["players"].as_object().unwrap();
+        assert_eq!(players.len(), 1);
+        assert!(players.contains_key(&Uuid::from_u128(1).to_string()));
+        assert!(!players.contains_key(&Uuid::nil().to_string()));
+        assert_eq!(json["global"]["bedwars"]["rounds"], 3.0);
+    }
+
+    #[test]
+    fn legacy_stats_store_global_stats_under_the_nil_uuid() {
+        let global = HashMap::from([(
+            "bedwars".to_owned(),
+            HashMap::from([("rounds".to_owned(), 3.0)]),
+        )]);
+        let json = serde_json::to_value(game_stats(global).into_legacy()).unwrap();
+
+        assert_eq!(json.as_object().unwrap().len(), 2);
+        assert_eq!(
+            json[Uuid::from_u128(1).to_string()]["bedwars"]["kills"],
+            5.0
+        );
+        assert_eq!(json[Uuid::nil().to_string()]["bedwars"]["rounds"], 3.0);
+    }
+
+    #[test]
+    fn legacy_stats_omit_the_nil_uuid_without_global_stats() {
+        let json = serde_json::to_value(game_stats(HashMap::new()).into_legacy()).unwrap();
+
+        let players = json.as_object().unwrap();
+        assert_eq!(players.len(), 1);
+        assert!(!players.contains_key(&Uuid::nil().to_string()));
+    }
 }
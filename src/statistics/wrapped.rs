@@ -1,9 +1,17 @@
 use std::convert::TryFrom;
 
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use uuid::Uuid;
 
-use super::database::{StatisticsDatabaseError, StatisticsDatabaseResult};
+use super::database::{query_logged, StatisticsDatabaseError, StatisticsDatabaseResult};
+use super::model::RecentGame;
+
+/// How many wrapped queries may run at once, which keeps `build_wrapped` from taking more than
+/// this many connections from the ClickHouse pool.
+const MAX_CONCURRENT_QUERIES: usize = 4;
 
 #[derive(Clone, Copy)]
 pub enum WrappedYear {
@@ -23,13 +31,48 @@ impl TryFrom<u16> for WrappedYear {
     }
 }
 
+/// Which of a player's games to find, by when it was played.
+#[derive(Clone, Copy)]
+enum GameAt {
+    First,
+    Last,
+}
+
+impl GameAt {
+    fn order_sql(self) -> &'static str {
+        match self {
+            GameAt::First => "ASC",
+            GameAt::Last => "DESC",
+        }
+    }
+}
+
 pub struct NucleoidWrapped {
     clickhouse_pool: clickhouse_rs::Pool,
+    query_permits: Semaphore,
 }
 
 impl NucleoidWrapped {
     pub fn new(clickhouse_pool: clickhouse_rs::Pool) -> Self {
-        Self { clickhouse_pool }
+        Self {
+            clickhouse_pool,
+            query_permits: Semaphore::new(MAX_CONCURRENT_QUERIES),
+        }
+    }
+
+    /// Takes a handle for a single query, first waiting until fewer than `MAX_CONCURRENT_QUERIES`
+    /// are running. The permit must be held for as long as the handle is in use.
+    async fn get_handle(
+        &self,
+    ) -> Result<(SemaphorePermit<'_>, clickhouse_rs::ClientHandle), clickhouse_rs::errors::Error>
+    {
+        let permit = self
+            .query_permits
+            .acquire()
+            .await
+            .expect("wrapped query semaphore is never closed");
+        let handle = self.clickhouse_pool.get_handle().await?;
+        Ok((permit, handle))
     }
 
     fn start_date(year: WrappedYear) -> &'static str {
@@ -54,21 +97,43 @@ impl NucleoidWrapped {
         )
     }
 
-    async fn played_count(&self, player: Uuid, year: WrappedYear) -> Result<u64, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle.query(format!(
-            r#"
+    /// Restricts games to the given year and, if given, to a single namespace.
+    fn filter(year: WrappedYear, namespace: Option<&str>) -> String {
+        match namespace {
+            // safety: namespaces are validated to only contain alphanumerics and underscores
+            Some(namespace) => format!(
+                "{} AND (games.namespace = '{}')",
+                Self::date_range(year),
+                namespace
+            ),
+            None => Self::date_range(year),
+        }
+    }
+
+    async fn played_count(
+        &self,
+        player: Uuid,
+        year: WrappedYear,
+        namespace: Option<&str>,
+    ) -> Result<u64, clickhouse_rs::errors::Error> {
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
             SELECT
                 COUNT(DISTINCT game_id) AS total
             FROM player_statistics
             INNER JOIN games ON player_statistics.game_id = games.game_id
-            WHERE (player_id = '{player_id}') AND {date_range}
+            WHERE (player_id = '{player_id}') AND {filter}
             ORDER BY total DESC
             "#,
-            // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
-            player_id = player,
-            date_range = Self::date_range(year),
-        )).fetch_all().await?;
+                // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
+                player_id = player,
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
         if let Some(row) = results.rows().next() {
             Ok(row.get("total")?)
         } else {
@@ -80,23 +145,28 @@ impl NucleoidWrapped {
         &self,
         player: Uuid,
         year: WrappedYear,
+        namespace: Option<&str>,
     ) -> Result<Vec<PerGameStat>, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle.query(format!(
-            r#"
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
             SELECT
                 games.namespace as namespace,
                 COUNT(DISTINCT game_id) AS total
             FROM player_statistics
             INNER JOIN games ON player_statistics.game_id = games.game_id
-            WHERE (player_id = '{player_id}') AND {date_range}
+            WHERE (player_id = '{player_id}') AND {filter}
             GROUP BY games.namespace
             ORDER BY total DESC
             "#,
-            // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
-            player_id = player,
-            date_range = Self::date_range(year),
-        )).fetch_all().await?;
+                // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
+                player_id = player,
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
 
         let mut top_games = Vec::with_capacity(results.row_count());
 
@@ -109,21 +179,30 @@ impl NucleoidWrapped {
         Ok(top_games)
     }
 
-    async fn days_played(&self, player: Uuid, year: WrappedYear,) -> Result<u64, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle.query(format!(
-            r#"
+    async fn days_played(
+        &self,
+        player: Uuid,
+        year: WrappedYear,
+        namespace: Option<&str>,
+    ) -> Result<u64, clickhouse_rs::errors::Error> {
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
             SELECT
                 COUNT(DISTINCT toDayOfYear(date_played)) AS total
             FROM player_statistics
             INNER JOIN games ON player_statistics.game_id = games.game_id
-            WHERE (player_id = '{player_id}') AND {date_range}
+            WHERE (player_id = '{player_id}') AND {filter}
             ORDER BY total DESC
             "#,
-            // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
-            player_id = player,
-            date_range = Self::date_range(year),
-        )).fetch_all().await?;
+                // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
+                player_id = player,
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
         if let Some(row) = results.rows().next() {
             Ok(row.get("total")?)
         } else {
@@ -135,23 +214,28 @@ impl NucleoidWrapped {
         &self,
         player: Uuid,
         year: WrappedYear,
+        namespace: Option<&str>,
     ) -> Result<Vec<PerGameStat>, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle.query(format!(
-            r#"
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
             SELECT
                 games.namespace as namespace,
                 COUNT(DISTINCT toDayOfYear(date_played)) AS total
             FROM player_statistics
             INNER JOIN games ON player_statistics.game_id = games.game_id
-            WHERE (player_id = '{player_id}') AND {date_range}
+            WHERE (player_id = '{player_id}') AND {filter}
             GROUP BY games.namespace
             ORDER BY total DESC
             "#,
-            // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
-            player_id = player,
-            date_range = Self::date_range(year),
-        )).fetch_all().await?;
+                // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
+                player_id = player,
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
 
         let mut top_games = Vec::with_capacity(results.row_count());
 
@@ -164,10 +248,19 @@ impl NucleoidWrapped {
         Ok(top_games)
     }
 
-    async fn most_players(&self, player: Uuid, year: WrappedYear,) -> Result<u64, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle
-            .query(format!(
+    /// Counts the distinct other players the player has shared a game with. The player is excluded
+    /// in the query itself rather than subtracted afterwards, so someone who has only played alone
+    /// gets 0 instead of underflowing.
+    async fn most_players(
+        &self,
+        player: Uuid,
+        year: WrappedYear,
+        namespace: Option<&str>,
+    ) -> Result<u64, clickhouse_rs::errors::Error> {
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
                 r#"
                 SELECT
                     COUNT(DISTINCT player_id) as total
@@ -176,36 +269,35 @@ impl NucleoidWrapped {
                         game_id
                     FROM player_statistics
                     INNER JOIN games ON player_statistics.game_id = games.game_id
-                    WHERE (player_id = '{player_id}') AND {date_range}
+                    WHERE (player_id = '{player_id}') AND {filter}
                     GROUP BY game_id) AS games
                 INNER JOIN player_statistics ON player_statistics.game_id = games.game_id
+                WHERE player_statistics.player_id != '{player_id}'
             "#,
                 // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
                 player_id = player,
-                date_range = Self::date_range(year),
-            ))
-            .fetch_all()
-            .await?;
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
         if let Some(row) = results.rows().next() {
-            let mut total: u64 = row.get("total")?;
-            // lets maybe not crash here
-            if total > 0 {
-                total -= 1;
-            }
-            Ok(total)
+            Ok(row.get("total")?)
         } else {
             Ok(0)
         }
     }
 
+    /// Like `most_players`, but counted separately for each namespace.
     async fn most_players_games(
         &self,
         player: Uuid,
         year: WrappedYear,
+        namespace: Option<&str>,
     ) -> Result<Vec<PerGameStat>, clickhouse_rs::errors::Error> {
-        let mut ch_handle = self.clickhouse_pool.get_handle().await?;
-        let results = ch_handle
-            .query(format!(
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
                 r#"
                 SELECT
                     COUNT(DISTINCT player_id) as total,
@@ -216,44 +308,131 @@ impl NucleoidWrapped {
                         namespace
                     FROM player_statistics
                     INNER JOIN games ON player_statistics.game_id = games.game_id
-                    WHERE (player_id = '{player_id}') AND {date_range}
+                    WHERE (player_id = '{player_id}') AND {filter}
                     GROUP BY game_id, namespace) AS games
                 INNER JOIN player_statistics ON player_statistics.game_id = games.game_id
+                WHERE player_statistics.player_id != '{player_id}'
                 GROUP BY namespace
                 ORDER BY total DESC
             "#,
                 // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
                 player_id = player,
-                date_range = Self::date_range(year),
-            ))
-            .fetch_all()
-            .await?;
+                filter = Self::filter(year, namespace),
+            ),
+        )
+        .await?;
 
         let mut top_games = Vec::with_capacity(results.row_count());
 
         for row in results.rows() {
             let namespace: String = row.get("namespace")?;
-            let mut total: u64 = row.get("total")?;
-            total -= 1;
+            let total = row.get("total")?;
             top_games.push(PerGameStat { namespace, total });
         }
 
         Ok(top_games)
     }
 
+    /// Finds the player's first or last game.
+    async fn game_at(
+        &self,
+        player: Uuid,
+        year: WrappedYear,
+        namespace: Option<&str>,
+        at: GameAt,
+    ) -> Result<Option<RecentGame>, clickhouse_rs::errors::Error> {
+        let (_permit, mut ch_handle) = self.get_handle().await?;
+        let results = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
+                SELECT
+                    games.game_id AS game_id,
+                    games.namespace AS namespace,
+                    games.server AS server,
+                    games.date_played AS date_played,
+                    games.duration_seconds AS duration_seconds
+                FROM player_statistics
+                INNER JOIN games ON player_statistics.game_id = games.game_id
+                WHERE (player_id = '{player_id}') AND {filter}
+                ORDER BY date_played {order}
+                LIMIT 1
+            "#,
+                // safety: player is a uuid, which has a fixed format which is safe to insert directly into the sql
+                player_id = player,
+                filter = Self::filter(year, namespace),
+                order = at.order_sql(),
+            ),
+        )
+        .await?;
+
+        let row = match results.rows().next() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let id: Uuid = row.get("game_id")?;
+        let namespace: String = row.get("namespace")?;
+        let server: String = row.get("server")?;
+        let date_played: DateTime<Tz> = row.get("date_played")?;
+        let duration_seconds: Option<u32> = row.get("duration_seconds")?;
+
+        let players_res = query_logged(
+            &mut ch_handle,
+            format!(
+                r#"
+                SELECT player_id
+                FROM player_statistics
+                WHERE game_id = '{}'
+                GROUP BY player_id
+            "#,
+                id
+            ),
+        )
+        .await?;
+        let mut players = Vec::with_capacity(players_res.row_count());
+        for row in players_res.rows() {
+            players.push(row.get("player_id")?);
+        }
+
+        Ok(Some(RecentGame {
+            id,
+            namespace,
+            players,
+            server,
+            date_played: date_played.with_timezone(&Utc),
+            duration_seconds,
+        }))
+    }
+
     pub async fn build_wrapped(
         &self,
         player: Uuid,
         year: u16,
+        namespace: Option<&str>,
     ) -> StatisticsDatabaseResult<PlayerWrappedData> {
         let year = WrappedYear::try_from(year)?;
 
-        let played_count = self.played_count(player, year).await?;
-        let top_games = self.top_games(player, year).await?;
-        let days_played = self.days_played(player, year).await?;
-        let days_played_games = self.days_played_games(player, year).await?;
-        let most_players = self.most_players(player, year).await?;
-        let most_players_games = self.most_players_games(player, year).await?;
+        // These are independent, so run them concurrently. Each takes its own handle from the
+        // pool, but no more than `MAX_CONCURRENT_QUERIES` at a time.
+        let (
+            played_count,
+            top_games,
+            days_played,
+            days_played_games,
+            most_players,
+            most_players_games,
+            first_game,
+            last_game,
+        ) = tokio::try_join!(
+            self.played_count(player, year, namespace),
+            self.top_games(player, year, namespace),
+            self.days_played(player, year, namespace),
+            self.days_played_games(player, year, namespace),
+            self.most_players(player, year, namespace),
+            self.most_players_games(player, year, namespace),
+            self.game_at(player, year, namespace, GameAt::First),
+            self.game_at(player, year, namespace, GameAt::Last),
+        )?;
         Ok(PlayerWrappedData {
             played_count,
             top_games,
@@ -261,6 +440,8 @@ impl NucleoidWrapped {
             days_played_games,
             most_players,
             most_players_games,
+            first_game,
+            last_game,
         })
     }
 }
@@ -273,6 +454,8 @@ pub struct PlayerWrappedData {
     days_played_games: Vec<PerGameStat>,
     most_players: u64,
     most_players_games: Vec<PerGameStat>,
+    first_game: Option<RecentGame>,
+    last_game: Option<RecentGame>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -1,22 +1,86 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
 use std::num::NonZeroUsize;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
-use warp::http::StatusCode;
+use warp::filters::BoxedFilter;
+use warp::http::{StatusCode, Uri};
 use warp::Filter;
 use xtra::prelude::*;
 
 use crate::controller::*;
-use crate::mojang_api::{GetPlayerUsername, MojangApiClient};
+use crate::model::ServerStatus;
+use crate::mojang_api::{
+    ClientError, ForgetPlayer, GetPlayerUsername, MojangApiClient, Platform, SearchPlayers,
+};
 use crate::statistics::database::*;
-use crate::statistics::model::DataQueryType;
+use crate::statistics::leaderboards::database::DEFAULT_LEADERBOARD_LIMIT;
+use crate::statistics::leaderboards::LeaderboardOrder;
+use crate::statistics::model::{
+    is_valid_namespace, is_valid_server, DataQueryType, GameStatsResponse,
+};
 use crate::WebServerConfig;
 
+mod client_ip;
+mod csv;
+
+use client_ip::{client_ip, Cidr, ClientIpResolver};
+use csv::StatsFormat;
+
+/// The maximum number of sub-requests that can be made in a single batch request.
+const MAX_BATCH_SIZE: usize = 16;
+/// The maximum number of leaderboards that can be fetched in a single request.
+const MAX_LEADERBOARD_BATCH_SIZE: usize = 16;
+/// The body limit for toggling maintenance mode. This stays fixed rather than following
+/// `max_body_bytes`, since the body is only ever `{"enabled": bool}` and raising the limit for
+/// large stats uploads shouldn't raise it here.
+const MAINTENANCE_BODY_LIMIT: u64 = 1024;
+
+/// How long clients may cache a player's username for, in seconds.
+const USERNAME_CACHE_MAX_AGE: u32 = 60 * 60;
+/// The `Retry-After` value used when the Mojang API rate limits us without giving one, in seconds.
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
 pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
-    let cors = warp::cors().allow_any_origin();
+    let cors = warp::cors()
+        .allow_any_origin()
+        .expose_headers(["X-Leaderboard-Updated"]);
+
+    // Requests with larger bodies are rejected with 413 Payload Too Large
+    let max_body_bytes = config.max_body_bytes;
+
+    let mojang_cache_size =
+        NonZeroUsize::new(config.mojang_cache_size).expect("mojang_cache_size must be nonzero");
+    let mojang_cache_ttl = Duration::from_secs(config.mojang_cache_ttl_hours * 60 * 60);
 
-    let mojang_client = MojangApiClient::start(NonZeroUsize::new(512).unwrap())
-        .expect("failed to create Mojang API client");
+    let trusted_proxies = config
+        .trusted_proxies
+        .iter()
+        .map(|cidr| cidr.parse::<Cidr>())
+        .collect::<Result<Vec<_>, _>>()
+        .expect("trusted_proxies must be valid CIDR ranges");
+    let client_ip_resolver =
+        ClientIpResolver::new(config.client_ip_header.clone(), trusted_proxies);
+
+    let mojang_request_timeout = Duration::from_secs(config.mojang_request_timeout_seconds);
+
+    let mojang_client = MojangApiClient::start(
+        mojang_cache_size,
+        mojang_cache_ttl,
+        mojang_request_timeout,
+        config.bedrock_username_url.clone(),
+    )
+    .await
+    .expect("failed to create Mojang API client");
+
+    controller
+        .send(RegisterMojangApiClient {
+            client: mojang_client.clone(),
+        })
+        .await
+        .expect("controller disconnected");
 
     let status = warp::path("status")
         .and(warp::path::param())
@@ -26,31 +90,93 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
         })
         .with(&cors);
 
+    let recent_chat = warp::path("chat")
+        .and(warp::path::param())
+        .and(warp::path("recent"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and_then({
+            let controller = controller.clone();
+            move |channel, query: RecentChatQuery| {
+                get_recent_chat(controller.clone(), channel, query)
+            }
+        })
+        .with(&cors);
+
+    let all_statuses = warp::path("status")
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move || get_all_statuses(controller.clone())
+        })
+        .with(&cors);
+
+    let capabilities = warp::path("capabilities")
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move || get_capabilities(controller.clone())
+        })
+        .with(&cors);
+
     let player_game_stats = warp::path("stats")
         .and(warp::path("player"))
         .and(warp::path::param::<Uuid>())
         .and(warp::path::param::<String>())
+        .and(warp::query())
         .and_then({
             let controller = controller.clone();
-            move |uuid, namespace| get_player_stats(controller.clone(), uuid, Some(namespace))
+            move |uuid, namespace, query: PlayerStatsQuery| {
+                get_player_stats(controller.clone(), uuid, Some(namespace), query)
+            }
         })
         .with(&cors);
 
     let all_player_game_stats = warp::path("stats")
         .and(warp::path("player"))
         .and(warp::path::param::<Uuid>())
+        .and(warp::query())
         .and_then({
             let controller = controller.clone();
-            move |uuid| get_player_stats(controller.clone(), uuid, None)
+            let default_namespace = config.default_namespace.clone();
+            move |uuid, query: PlayerStatsQuery| {
+                let namespace = default_namespace.clone().filter(|_| !query.all_namespaces);
+                get_player_stats(controller.clone(), uuid, namespace, query)
+            }
         })
         .with(&cors);
 
     let all_game_stats = warp::path("stats")
         .and(warp::path("game"))
         .and(warp::path::param::<Uuid>())
+        .and(warp::query())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid, query: GameStatsQuery| get_game_stats(controller.clone(), uuid, query)
+        })
+        .with(&cors);
+
+    let all_game_stats_v2 = warp::path("v2")
+        .and(warp::path("stats"))
+        .and(warp::path("game"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::query())
+        .and_then({
+            let controller = controller.clone();
+            move |uuid, query: GameStatsQuery| get_game_stats_v2(controller.clone(), uuid, query)
+        })
+        .with(&cors);
+
+    let games_stats = warp::path("stats")
+        .and(warp::path("games"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::json())
         .and_then({
             let controller = controller.clone();
-            move |uuid| get_game_stats(controller.clone(), uuid)
+            let config = config.clone();
+            move |game_ids: Vec<Uuid>| get_games_stats(controller.clone(), config.clone(), game_ids)
         })
         .with(&cors);
 
@@ -66,18 +192,45 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
         })
         .with(&cors);
 
+    let count_leaderboard = warp::path("leaderboard")
+        .and(warp::path::param::<String>())
+        .and(warp::path("count"))
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move |id| count_leaderboard(controller.clone(), id)
+        })
+        .with(&cors);
+
     let get_leaderboard = warp::path("leaderboard")
         .and(warp::path::param::<String>())
+        .and(warp::query())
+        .and_then({
+            let controller = controller.clone();
+            move |id, query: LeaderboardQuery| get_leaderboard(controller.clone(), id, query)
+        })
+        .with(&cors);
+
+    let leaderboards_batch = warp::path("leaderboards")
+        .and(warp::path("batch"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::json())
         .and_then({
             let controller = controller.clone();
-            move |id| get_leaderboard(controller.clone(), id)
+            let config = config.clone();
+            move |body: LeaderboardsBatchBody| {
+                leaderboards_batch(controller.clone(), config.clone(), body)
+            }
         })
         .with(&cors);
 
     let list_leaderboards = warp::path("leaderboards")
+        .and(warp::query())
         .and_then({
             let controller = controller.clone();
-            move || list_leaderboards(controller.clone())
+            move |query: ListLeaderboardsQuery| list_leaderboards(controller.clone(), query)
         })
         .with(&cors);
 
@@ -90,6 +243,66 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
         })
         .with(&cors);
 
+    let delete_player_stats = warp::path("stats")
+        .and(warp::path("player"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(authorized(config.admin_token.clone()))
+        .and(warp::query())
+        .and_then({
+            let controller = controller.clone();
+            let mojang_client = mojang_client.clone();
+            move |uuid, authorized, query: DeletePlayerStatsQuery| {
+                delete_player_stats(
+                    controller.clone(),
+                    mojang_client.clone(),
+                    authorized,
+                    uuid,
+                    query,
+                )
+            }
+        })
+        .with(&cors);
+
+    let get_maintenance = warp::path("admin")
+        .and(warp::path("maintenance"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(authorized(config.admin_token.clone()))
+        .and_then({
+            let controller = controller.clone();
+            move |authorized| get_maintenance(controller.clone(), authorized)
+        })
+        .with(&cors);
+
+    let set_maintenance = warp::path("admin")
+        .and(warp::path("maintenance"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(authorized(config.admin_token.clone()))
+        .and(warp::body::content_length_limit(MAINTENANCE_BODY_LIMIT))
+        .and(warp::body::json())
+        .and_then({
+            let controller = controller.clone();
+            move |authorized, body: MaintenanceBody| {
+                set_maintenance(controller.clone(), authorized, body)
+            }
+        })
+        .with(&cors);
+
+    let rebuild_leaderboards = warp::path("admin")
+        .and(warp::path("leaderboards"))
+        .and(warp::path("rebuild"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(authorized(config.admin_token.clone()))
+        .and_then({
+            let controller = controller.clone();
+            move |authorized| rebuild_leaderboards(controller.clone(), authorized)
+        })
+        .with(&cors);
+
     let get_statistics_stats = warp::path("stats")
         .and(warp::path("stats"))
         .and_then({
@@ -98,6 +311,25 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
         })
         .with(&cors);
 
+    let get_ingestion_rate = warp::path("stats")
+        .and(warp::path("ingestion"))
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move || get_ingestion_rate(controller.clone())
+        })
+        .with(&cors);
+
+    let list_servers = warp::path("stats")
+        .and(warp::path("servers"))
+        .and(warp::path("list"))
+        .and(warp::path::end())
+        .and_then({
+            let controller = controller.clone();
+            move || list_servers(controller.clone())
+        })
+        .with(&cors);
+
     let data_query = warp::path("stats")
         .and(warp::path("data"))
         .and(warp::path("query"))
@@ -112,9 +344,21 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
     let get_player_username = warp::path("player")
         .and(warp::path::param::<Uuid>())
         .and(warp::path("username"))
+        .and(warp::query())
+        .and_then({
+            let mojang_client = mojang_client.clone();
+            move |id, query: UsernameQuery| {
+                get_player_username(mojang_client.clone(), id, query.platform)
+            }
+        })
+        .with(&cors);
+
+    let search_players = warp::path("players")
+        .and(warp::path("search"))
+        .and(warp::query())
         .and_then({
             let mojang_client = mojang_client.clone();
-            move |id| get_player_username(mojang_client.clone(), id)
+            move |query: SearchPlayersQuery| search_players(mojang_client.clone(), query.q)
         })
         .with(&cors);
 
@@ -124,28 +368,164 @@ pub async fn run(controller: Address<Controller>, config: WebServerConfig) {
         .and(warp::query())
         .and_then({
             let controller = controller.clone();
-            move |id, query: WrappedQuery| nucleoid_wrapped(controller.clone(), id, query.year)
+            move |id, query: WrappedQuery| nucleoid_wrapped(controller.clone(), id, query)
         })
         .with(&cors);
 
     let combined = status
+        .or(all_statuses)
+        .or(recent_chat)
+        .or(capabilities)
+        .or(delete_player_stats)
+        .or(get_maintenance)
+        .or(set_maintenance)
+        .or(rebuild_leaderboards)
         .or(player_game_stats)
         .or(all_player_game_stats)
         .or(all_game_stats)
+        .or(all_game_stats_v2)
+        .or(games_stats)
         .or(get_recent_games)
         .or(get_statistics_stats)
+        .or(get_ingestion_rate)
+        .or(list_servers)
+        .or(count_leaderboard)
         .or(get_leaderboard)
+        .or(leaderboards_batch)
         .or(list_leaderboards)
         .or(get_player_rankings)
         .or(data_query)
         .or(get_player_username)
-        .or(nucleoid_wrapped);
+        .or(search_players)
+        .or(nucleoid_wrapped)
+        .recover(recover_unavailable)
+        .map(box_reply)
+        .boxed();
+
+    let batch = warp::path("batch")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::json())
+        .and_then({
+            let api = combined.clone();
+            move |requests: Vec<BatchRequest>| batch(api.clone(), requests)
+        })
+        .with(&cors);
+
+    let combined = request_id()
+        .and(client_ip(client_ip_resolver))
+        .and(combined.or(batch))
+        .map(|request_id, _client_ip, reply| with_request_id(request_id, reply))
+        .boxed()
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = info.path(),
+                request_id = tracing::field::Empty,
+                client_ip = tracing::field::Empty,
+            )
+        }));
 
     warp::serve(combined)
         .run(([127, 0, 0, 1], config.port))
         .await;
 }
 
+/// Generates an id for the current request and records it on the request's tracing span, so
+/// that all logs produced while handling the request can be correlated. Actor handlers invoked
+/// by the request inherit this span through xtra's instrumentation.
+fn request_id() -> impl Filter<Extract = (Uuid,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(|| {
+        let request_id = Uuid::new_v4();
+        tracing::Span::current().record("request_id", tracing::field::display(request_id));
+        request_id
+    })
+}
+
+fn box_reply(reply: impl warp::Reply + 'static) -> Box<dyn warp::Reply> {
+    Box::new(reply)
+}
+
+fn with_request_id(request_id: Uuid, reply: impl warp::Reply + 'static) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_header(
+        reply,
+        "X-Request-Id",
+        request_id.to_string(),
+    ))
+}
+
+/// Runs multiple GET requests against the API at once, returning their results in order.
+async fn batch(
+    api: BoxedFilter<(Box<dyn warp::Reply>,)>,
+    requests: Vec<BatchRequest>,
+) -> ApiResult {
+    if requests.len() > MAX_BATCH_SIZE {
+        return Ok(send_http_status(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    let responses = requests.into_iter().map(|request| {
+        let api = api.clone();
+        async move {
+            if !request.method.eq_ignore_ascii_case("GET") {
+                return BatchResponse::status(StatusCode::METHOD_NOT_ALLOWED);
+            }
+            if !request.path.starts_with('/') || request.path.parse::<Uri>().is_err() {
+                return BatchResponse::status(StatusCode::BAD_REQUEST);
+            }
+
+            // Despite its name, `warp::test::request` is warp's public way of running a filter
+            // against a request built in-process, and is safe to use outside of tests. Sub-requests
+            // deliberately skip the request id and client IP filters: they run inside the span of
+            // the batch request, so their logs carry its request id and client IP, and the batch
+            // response is the one that gets the `X-Request-Id` header.
+            let response = warp::test::request()
+                .method("GET")
+                .path(&request.path)
+                .reply(&api)
+                .await;
+
+            let body = response.body();
+            BatchResponse {
+                status: response.status().as_u16(),
+                body: serde_json::from_slice(body).unwrap_or_else(|_| {
+                    serde_json::Value::String(String::from_utf8_lossy(body).into_owned())
+                }),
+            }
+        }
+    });
+
+    let responses = futures::future::join_all(responses).await;
+    Ok(Box::new(warp::reply::json(&responses)))
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    path: String,
+    #[serde(default = "default_batch_method")]
+    method: String,
+}
+
+fn default_batch_method() -> String {
+    "GET".to_owned()
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    status: u16,
+    body: serde_json::Value,
+}
+
+impl BatchResponse {
+    fn status(status: StatusCode) -> Self {
+        BatchResponse {
+            status: status.as_u16(),
+            body: serde_json::Value::Null,
+        }
+    }
+}
+
 async fn get_status(controller: Address<Controller>, channel: String) -> ApiResult {
     match controller.send(GetStatus(channel)).await {
         Ok(status) => Ok(match status {
@@ -159,35 +539,147 @@ async fn get_status(controller: Address<Controller>, channel: String) -> ApiResu
     }
 }
 
+async fn get_capabilities(controller: Address<Controller>) -> ApiResult {
+    let capabilities = controller
+        .send(GetCapabilities)
+        .await
+        .expect("controller disconnected");
+    Ok(Box::new(warp::reply::json(&capabilities)))
+}
+
+async fn get_all_statuses(controller: Address<Controller>) -> ApiResult {
+    match controller.send(GetAllStatuses).await {
+        Ok(servers) => {
+            let total_players = servers.values().map(|status| status.players.len()).sum();
+            Ok(Box::new(warp::reply::json(&AllStatusesResponse {
+                servers,
+                total_players,
+            })))
+        }
+        Err(err) => Ok(Box::new(warp::reply::with_status(
+            format!("{:?}", err),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ))),
+    }
+}
+
+#[derive(Serialize)]
+struct AllStatusesResponse {
+    servers: HashMap<String, ServerStatus>,
+    total_players: usize,
+}
+
 type ApiResult = Result<Box<dyn warp::Reply>, warp::Rejection>;
 
 async fn get_player_stats(
     controller: Address<Controller>,
     uuid: Uuid,
     namespace: Option<String>,
+    query: PlayerStatsQuery,
 ) -> ApiResult {
     let statistics = get_statistics_controller(controller).await?;
 
     if let Some(namespace) = &namespace {
-        for c in namespace.chars() {
-            if !(c.is_ascii_lowercase() || c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
-            {
-                return Ok(send_http_status(StatusCode::BAD_REQUEST));
-            }
+        if !is_valid_namespace(namespace) {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
         }
+    } else if query.all_keys {
+        // Keys are only filled in for a single namespace, which must be given in the path
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
     }
 
     let res = statistics
-        .send(GetPlayerStats { uuid, namespace })
+        .send(GetPlayerStats {
+            uuid,
+            namespace,
+            all_keys: query.all_keys,
+        })
         .await
         .unwrap();
-    handle_option_result(res)
+    match (query.format, res) {
+        (StatsFormat::Csv, Ok(Some(stats))) => Ok(csv::reply(csv::player_stats(&stats))),
+        (_, res) => handle_option_result(res),
+    }
 }
 
-async fn get_game_stats(controller: Address<Controller>, uuid: Uuid) -> ApiResult {
+async fn get_game_stats(
+    controller: Address<Controller>,
+    uuid: Uuid,
+    query: GameStatsQuery,
+) -> ApiResult {
     let statistics = get_statistics_controller(controller).await?;
-    let res = statistics.send(GetGameStats(uuid)).await.unwrap();
-    handle_option_result(res)
+    if !query.is_valid() {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics
+        .send(GetGameStats {
+            uuid,
+            namespace: query.namespace,
+        })
+        .await
+        .unwrap();
+    match (query.format, res) {
+        (StatsFormat::Csv, Ok(Some(stats))) => Ok(csv::reply(csv::game_stats(&stats))),
+        (_, res) => {
+            handle_option_result(res.map(|stats| stats.map(GameStatsResponse::into_legacy)))
+        }
+    }
+}
+
+async fn get_game_stats_v2(
+    controller: Address<Controller>,
+    uuid: Uuid,
+    query: GameStatsQuery,
+) -> ApiResult {
+    let statistics = get_statistics_controller(controller).await?;
+    if !query.is_valid() {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let res = statistics
+        .send(GetGameStats {
+            uuid,
+            namespace: query.namespace,
+        })
+        .await
+        .unwrap();
+    match (query.format, res) {
+        (StatsFormat::Csv, Ok(Some(stats))) => Ok(csv::reply(csv::game_stats(&stats))),
+        (_, res) => handle_option_result(res),
+    }
+}
+
+async fn get_recent_chat(
+    controller: Address<Controller>,
+    channel: String,
+    query: RecentChatQuery,
+) -> ApiResult {
+    let recent_chat = controller
+        .send(GetRecentChat {
+            channel,
+            limit: query.limit.min(MAX_RECENT_CHAT),
+        })
+        .await
+        .expect("controller disconnected");
+    Ok(Box::new(warp::reply::json(&recent_chat)))
+}
+
+async fn get_games_stats(
+    controller: Address<Controller>,
+    config: WebServerConfig,
+    game_ids: Vec<Uuid>,
+) -> ApiResult {
+    if game_ids.len() > config.max_query_size as usize {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(GetGamesStats(game_ids))
+        .await
+        .expect("controller disconnected");
+    handle_result(res)
 }
 
 async fn get_recent_games(
@@ -205,6 +697,7 @@ async fn get_recent_games(
         .send(GetRecentGames {
             limit: query.limit,
             player_id: query.player,
+            since: query.since,
         })
         .await
         .unwrap();
@@ -220,19 +713,215 @@ async fn get_statistics_stats(controller: Address<Controller>) -> ApiResult {
     handle_result(res)
 }
 
-async fn get_leaderboard(controller: Address<Controller>, id: String) -> ApiResult {
+/// Returns how many statistics were ingested over the last minute, in total and by namespace.
+async fn get_ingestion_rate(controller: Address<Controller>) -> ApiResult {
+    let statistics = get_statistics_controller(controller).await?;
+    let rate = statistics
+        .send(GetIngestionRate)
+        .await
+        .expect("controller disconnected");
+    Ok(Box::new(warp::reply::json(&rate)))
+}
+
+async fn list_servers(controller: Address<Controller>) -> ApiResult {
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(GetServers)
+        .await
+        .expect("controller disconnected");
+    handle_result(res)
+}
+
+async fn get_leaderboard(
+    controller: Address<Controller>,
+    id: String,
+    query: LeaderboardQuery,
+) -> ApiResult {
     let statistics = get_statistics_controller(controller).await?;
+
+    if let Some(server) = &query.server {
+        if !is_valid_server(server) {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    // Leaderboards for a single server are computed on the fly, so are never out of date
+    let last_updated = if query.server.is_none() {
+        let res = statistics
+            .send(GetLeaderboardUpdated(id.clone()))
+            .await
+            .expect("controller disconnected");
+        match res {
+            Ok(last_updated) => last_updated,
+            Err(e) => return Ok(handle_server_error(&e)),
+        }
+    } else {
+        None
+    };
+
+    if let Some(player) = query.player {
+        // Rankings are only stored for the whole network
+        if query.server.is_some() {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
+        }
+
+        let res = statistics
+            .send(GetLeaderboardWithPlayer {
+                id,
+                formatted: query.formatted,
+                order: query.order,
+                player,
+            })
+            .await
+            .expect("controller disconnected");
+        return Ok(with_leaderboard_updated(
+            handle_option_result(res)?,
+            last_updated,
+        ));
+    }
+
     let res = statistics
-        .send(GetLeaderboard(id))
+        .send(GetLeaderboard {
+            id,
+            formatted: query.formatted,
+            order: query.order,
+            server: query.server,
+        })
         .await
         .expect("controller disconnected");
-    handle_option_result(res)
+    Ok(with_leaderboard_updated(
+        handle_option_result(res)?,
+        last_updated,
+    ))
+}
+
+/// Adds an `X-Leaderboard-Updated` header with when the leaderboard was last rebuilt, if known.
+fn with_leaderboard_updated(
+    reply: Box<dyn warp::Reply>,
+    last_updated: Option<SystemTime>,
+) -> Box<dyn warp::Reply> {
+    match last_updated {
+        Some(last_updated) => Box::new(warp::reply::with_header(
+            reply,
+            "X-Leaderboard-Updated",
+            DateTime::<Utc>::from(last_updated).to_rfc3339(),
+        )),
+        None => reply,
+    }
+}
+
+async fn leaderboards_batch(
+    controller: Address<Controller>,
+    config: WebServerConfig,
+    body: LeaderboardsBatchBody,
+) -> ApiResult {
+    if body.ids.len() > MAX_LEADERBOARD_BATCH_SIZE
+        || body.limit == 0
+        || body.limit > config.max_query_size
+    {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(GetLeaderboards {
+            ids: body.ids,
+            limit: body.limit as i64,
+        })
+        .await
+        .expect("controller disconnected");
+    handle_result(res)
 }
 
-async fn list_leaderboards(controller: Address<Controller>) -> ApiResult {
+async fn delete_player_stats(
+    controller: Address<Controller>,
+    mojang_client: Address<MojangApiClient>,
+    authorized: bool,
+    uuid: Uuid,
+    query: DeletePlayerStatsQuery,
+) -> ApiResult {
+    if !authorized {
+        return Ok(send_http_status(StatusCode::UNAUTHORIZED));
+    }
+
     let statistics = get_statistics_controller(controller).await?;
     let res = statistics
-        .send(GetAllLeaderboards)
+        .send(DeletePlayerStats(uuid))
+        .await
+        .expect("controller disconnected");
+
+    if res.is_ok() && query.forget_username {
+        mojang_client
+            .send(ForgetPlayer(uuid))
+            .await
+            .expect("Mojang client disconnected");
+    }
+
+    handle_result(res)
+}
+
+async fn get_maintenance(controller: Address<Controller>, authorized: bool) -> ApiResult {
+    if !authorized {
+        return Ok(send_http_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let enabled = controller
+        .send(GetMaintenance)
+        .await
+        .expect("controller disconnected");
+    Ok(Box::new(warp::reply::json(&MaintenanceBody { enabled })))
+}
+
+async fn set_maintenance(
+    controller: Address<Controller>,
+    authorized: bool,
+    body: MaintenanceBody,
+) -> ApiResult {
+    if !authorized {
+        return Ok(send_http_status(StatusCode::UNAUTHORIZED));
+    }
+
+    controller
+        .send(SetMaintenance(body.enabled))
+        .await
+        .expect("controller disconnected");
+    Ok(Box::new(warp::reply::json(&body)))
+}
+
+async fn rebuild_leaderboards(controller: Address<Controller>, authorized: bool) -> ApiResult {
+    if !authorized {
+        return Ok(send_http_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(RebuildLeaderboards)
+        .await
+        .expect("controller disconnected");
+    match res {
+        Ok(()) => Ok(send_http_status(StatusCode::NO_CONTENT)),
+        Err(e) => Ok(handle_server_error(&e)),
+    }
+}
+
+async fn count_leaderboard(controller: Address<Controller>, id: String) -> ApiResult {
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(CountLeaderboard(id))
+        .await
+        .expect("controller disconnected");
+    handle_option_result(res.map(|count| count.map(|count| serde_json::json!({ "count": count }))))
+}
+
+async fn list_leaderboards(
+    controller: Address<Controller>,
+    query: ListLeaderboardsQuery,
+) -> ApiResult {
+    let statistics = get_statistics_controller(controller).await?;
+    let res = statistics
+        .send(GetAllLeaderboards {
+            namespace: query.namespace,
+        })
         .await
         .expect("controller disconnected");
     Ok(Box::new(warp::reply::json(&res)))
@@ -256,30 +945,149 @@ async fn data_query(controller: Address<Controller>, query: DataQueryQuery) -> A
     handle_result(res.map(|r| serde_json::json!({ "data": r })))
 }
 
-async fn get_player_username(mojang_client: Address<MojangApiClient>, id: Uuid) -> ApiResult {
+async fn get_player_username(
+    mojang_client: Address<MojangApiClient>,
+    id: Uuid,
+    platform: Option<Platform>,
+) -> ApiResult {
     let profile = mojang_client
-        .send(GetPlayerUsername(id))
+        .send(GetPlayerUsername(id, platform))
+        .await
+        .expect("Mojang client disconnected");
+    match profile {
+        Ok(Some(profile)) => Ok(Box::new(warp::reply::with_header(
+            warp::reply::json(&profile),
+            "Cache-Control",
+            format!("public, max-age={}", USERNAME_CACHE_MAX_AGE),
+        ))),
+        Ok(None) => Err(warp::reject::not_found()),
+        Err(ClientError::RateLimited { retry_after }) => Ok(Box::new(warp::reply::with_header(
+            send_http_status(StatusCode::SERVICE_UNAVAILABLE),
+            "Retry-After",
+            retry_after.unwrap_or(DEFAULT_RETRY_AFTER).to_string(),
+        ))),
+        Err(ClientError::Timeout) => Ok(send_http_status(StatusCode::GATEWAY_TIMEOUT)),
+        Err(e) => Ok(handle_server_error(&e)),
+    }
+}
+
+/// Searches for players by username prefix. Note that results are limited to usernames which
+/// have previously been resolved by the backend.
+async fn search_players(mojang_client: Address<MojangApiClient>, query: String) -> ApiResult {
+    if query.is_empty() || query.len() > 16 {
+        return Ok(send_http_status(StatusCode::BAD_REQUEST));
+    }
+
+    let players = mojang_client
+        .send(SearchPlayers(query))
         .await
         .expect("Mojang client disconnected");
-    handle_option_result(profile)
+    Ok(Box::new(warp::reply::json(&players)))
 }
 
-async fn nucleoid_wrapped(controller: Address<Controller>, player_id: Uuid, year: Option<u16>) -> ApiResult {
+async fn nucleoid_wrapped(
+    controller: Address<Controller>,
+    player_id: Uuid,
+    query: WrappedQuery,
+) -> ApiResult {
     let statistics = get_statistics_controller(controller).await?;
+
+    if let Some(namespace) = &query.namespace {
+        if !is_valid_namespace(namespace) {
+            return Ok(send_http_status(StatusCode::BAD_REQUEST));
+        }
+    }
+
     let res = statistics
         .send(WrappedData {
             player_id,
-            year: year.unwrap_or(2023),
+            year: query.year.unwrap_or(2023),
+            namespace: query.namespace,
         })
         .await
         .expect("controller disconnected");
     handle_result(res)
 }
 
+#[derive(Deserialize)]
+struct PlayerStatsQuery {
+    #[serde(default)]
+    format: StatsFormat,
+    #[serde(default)]
+    all_keys: bool,
+    /// Return every namespace even if a default namespace is configured.
+    #[serde(default)]
+    all_namespaces: bool,
+}
+
+#[derive(Deserialize)]
+struct GameStatsQuery {
+    namespace: Option<String>,
+    #[serde(default)]
+    format: StatsFormat,
+}
+
+impl GameStatsQuery {
+    fn is_valid(&self) -> bool {
+        self.namespace.as_deref().is_none_or(is_valid_namespace)
+    }
+}
+
 #[derive(Deserialize)]
 struct RecentGamesQuery {
     limit: u32,
     player: Option<Uuid>,
+    /// An ISO 8601 timestamp, before which games are left out.
+    since: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct RecentChatQuery {
+    #[serde(default = "default_recent_chat_limit")]
+    limit: usize,
+}
+
+fn default_recent_chat_limit() -> usize {
+    50
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    #[serde(default)]
+    formatted: bool,
+    #[serde(default)]
+    order: LeaderboardOrder,
+    server: Option<String>,
+    /// Also include this player's ranking in the response.
+    player: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardsBatchBody {
+    ids: Vec<String>,
+    #[serde(default = "default_leaderboards_batch_limit")]
+    limit: u32,
+}
+
+fn default_leaderboards_batch_limit() -> u32 {
+    DEFAULT_LEADERBOARD_LIMIT as u32
+}
+
+#[derive(Deserialize)]
+struct DeletePlayerStatsQuery {
+    /// Also forget the player's cached username.
+    #[serde(default)]
+    forget_username: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MaintenanceBody {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct ListLeaderboardsQuery {
+    namespace: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -287,9 +1095,20 @@ struct DataQueryQuery {
     query: DataQueryType,
 }
 
+#[derive(Deserialize)]
+struct UsernameQuery {
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct SearchPlayersQuery {
+    q: String,
+}
+
 #[derive(Deserialize)]
 struct WrappedQuery {
     year: Option<u16>,
+    namespace: Option<String>,
 }
 
 async fn get_statistics_controller(
@@ -302,7 +1121,26 @@ async fn get_statistics_controller(
     {
         Ok(statistics)
     } else {
-        Err(warp::reject::not_found())
+        Err(warp::reject::custom(StatisticsUnavailable))
+    }
+}
+
+/// Rejection for routes that exist but whose subsystem isn't enabled.
+#[derive(Debug)]
+struct StatisticsUnavailable;
+
+impl warp::reject::Reject for StatisticsUnavailable {}
+
+/// Turns rejections for disabled subsystems into a 503, so that they can be told apart from
+/// routes that don't exist. Any other rejection is passed through.
+async fn recover_unavailable(rejection: warp::Rejection) -> ApiResult {
+    if rejection.find::<StatisticsUnavailable>().is_some() {
+        Ok(Box::new(warp::reply::with_status(
+            "Statistics are not enabled on this server",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )))
+    } else {
+        Err(rejection)
     }
 }
 
@@ -337,9 +1175,47 @@ where
     send_http_status(StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Extracts whether a request's `Authorization` header carries the configured admin token.
+fn authorized(
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (bool,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(move |authorization: Option<String>| {
+        match (&admin_token, authorization) {
+            (Some(token), Some(authorization)) => match authorization.strip_prefix("Bearer ") {
+                Some(given) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+                None => false,
+            },
+            _ => false,
+        }
+    })
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so that the admin token
+/// can't be guessed one byte at a time from how quickly it is rejected.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 fn send_http_status(status: StatusCode) -> Box<dyn warp::Reply> {
     Box::new(warp::reply::with_status(
         status.canonical_reason().unwrap_or(""),
         status,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens_exactly() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,274 @@
+//! Resolves the IP address of the client making a request, looking through any reverse proxies
+//! that we have been configured to trust.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use thiserror::Error;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+#[derive(Error, Debug)]
+#[error("invalid CIDR range: {0}")]
+pub struct InvalidCidr(String);
+
+/// A range of IP addresses, written as `address/prefix` or as a single address.
+#[derive(Copy, Clone, Debug)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(network), u32::from(ip), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(ip), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches<T>(network: T, ip: T, prefix: u8) -> bool
+where
+    T: std::ops::Shr<u32, Output = T> + PartialEq,
+{
+    let bits = (std::mem::size_of::<T>() * 8) as u32;
+    let shift = bits - prefix as u32;
+    // Shifting by the full width overflows, and a zero prefix matches everything anyway
+    shift == bits || network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_owned());
+
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let written = IpAddr::from_str(address.trim()).map_err(|_| invalid())?;
+        let max_prefix = if written.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        // IPv4-mapped addresses are matched as IPv4, so their prefix has to be converted to count
+        // from the start of the IPv4 address. A prefix shorter than the mapping itself would also
+        // cover addresses that aren't mapped, which can't be expressed as an IPv4 range.
+        let address = written.to_canonical();
+        let prefix = match (written, address) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => prefix.checked_sub(96).ok_or_else(invalid)?,
+            _ => prefix,
+        };
+
+        Ok(Cidr { address, prefix })
+    }
+}
+
+/// Resolves the client IP of a request from its socket peer and headers.
+#[derive(Clone, Debug)]
+pub struct ClientIpResolver {
+    header: String,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl ClientIpResolver {
+    pub fn new(header: String, trusted_proxies: Vec<Cidr>) -> Self {
+        ClientIpResolver {
+            header,
+            trusted_proxies,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client header is only consulted when the direct peer is a trusted proxy, as anybody
+    /// else could set it to whatever they like. Headers like `X-Forwarded-For` list every hop,
+    /// so we walk them from the closest hop and take the first address we don't trust.
+    pub fn resolve(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let forwarded = headers
+            .get_all(self.header.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| IpAddr::from_str(hop.trim()))
+            .collect::<Result<Vec<_>, _>>();
+
+        // A malformed header can't be trusted to tell us anything useful
+        let Ok(forwarded) = forwarded else {
+            return Some(peer);
+        };
+
+        let client = forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(forwarded.first())
+            .copied();
+
+        Some(client.unwrap_or(peer))
+    }
+}
+
+/// Extracts the resolved client IP of a request and records it on the request's tracing span.
+/// This is `None` if the request didn't come in over a socket with a known peer address.
+pub fn client_ip(
+    resolver: ClientIpResolver,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    let resolver = Arc::new(resolver);
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(move |peer: Option<SocketAddr>, headers: HeaderMap| {
+            let ip = resolver.resolve(peer, &headers);
+            if let Some(ip) = ip {
+                tracing::Span::current().record("client_ip", tracing::field::display(ip));
+            }
+            ip
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::HeaderValue;
+
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip(s), 443))
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn resolver() -> ClientIpResolver {
+        ClientIpResolver::new(
+            "x-forwarded-for".to_owned(),
+            vec![cidr("10.0.0.0/8"), cidr("fd00::/8")],
+        )
+    }
+
+    #[test]
+    fn parses_ranges_and_single_addresses() {
+        let range = cidr("192.168.1.0/24");
+        assert!(range.contains(ip("192.168.1.77")));
+        assert!(!range.contains(ip("192.168.2.1")));
+
+        let single = cidr(" 203.0.113.5 ");
+        assert!(single.contains(ip("203.0.113.5")));
+        assert!(!single.contains(ip("203.0.113.6")));
+
+        let v6 = cidr("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "nope/8",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything_in_its_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.5")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn matches_mapped_addresses_as_ipv4() {
+        let mapped = cidr("::ffff:10.0.0.0/104");
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(ip("11.0.0.1")));
+
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!("::ffff:10.0.0.0/64".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ignores_the_header_from_untrusted_peers() {
+        let headers = forwarded_for("198.51.100.1");
+        assert_eq!(
+            resolver().resolve(peer("203.0.113.5"), &headers),
+            Some(ip("203.0.113.5"))
+        );
+    }
+
+    #[test]
+    fn takes_the_closest_untrusted_hop_from_trusted_peers() {
+        let headers = forwarded_for("198.51.100.1, 203.0.113.5, 10.0.0.2");
+        assert_eq!(
+            resolver().resolve(peer("10.0.0.1"), &headers),
+            Some(ip("203.0.113.5"))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_peer_on_a_malformed_header() {
+        let headers = forwarded_for("198.51.100.1, not-an-ip");
+        assert_eq!(
+            resolver().resolve(peer("10.0.0.1"), &headers),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn takes_the_first_hop_when_every_hop_is_trusted() {
+        let headers = forwarded_for("10.0.0.3, fd00::2");
+        assert_eq!(
+            resolver().resolve(peer("10.0.0.1"), &headers),
+            Some(ip("10.0.0.3"))
+        );
+    }
+
+    #[test]
+    fn uses_the_peer_without_a_header() {
+        assert_eq!(
+            resolver().resolve(peer("10.0.0.1"), &HeaderMap::new()),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(resolver().resolve(None, &HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! Serializes statistics responses as CSV, for pulling them into spreadsheets.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use crate::statistics::model::{GameStatsResponse, PlayerStatsResponse};
+
+#[derive(Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Builds a `text/csv` reply from the given CSV body.
+pub fn reply(body: String) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_header(
+        body,
+        "Content-Type",
+        "text/csv; charset=utf-8",
+    ))
+}
+
+/// Writes `namespace,key,value` rows for a player's statistics.
+pub fn player_stats(stats: &PlayerStatsResponse) -> String {
+    let mut csv = String::from("namespace,key,value\n");
+    write_stats(&mut csv, None, stats);
+    csv
+}
+
+/// Writes `player,namespace,key,value` rows for a game's statistics. Global statistics are written
+/// with an empty player.
+pub fn game_stats(stats: &GameStatsResponse) -> String {
+    let mut csv = String::from("player,namespace,key,value\n");
+
+    let mut players = stats.players.iter().collect::<Vec<_>>();
+    players.sort_by_key(|(player, _)| **player);
+    for (player, player_stats) in players {
+        write_stats(&mut csv, Some(&player.to_string()), player_stats);
+    }
+    write_stats(&mut csv, Some(""), &stats.global);
+
+    csv
+}
+
+/// Writes a row for every statistic, sorted by namespace and key, optionally starting each row
+/// with the player column.
+fn write_stats(csv: &mut String, player: Option<&str>, stats: &PlayerStatsResponse) {
+    let mut rows = stats
+        .iter()
+        .flat_map(|(namespace, stats)| {
+            stats
+                .iter()
+                .map(move |(key, value)| (namespace, key, *value))
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+    for (namespace, key, value) in rows {
+        if let Some(player) = player {
+            let _ = write!(csv, "{},", player);
+        }
+        let _ = writeln!(csv, "{},{},{}", escape(namespace), escape(key), value);
+    }
+}
+
+/// Quotes a field if it contains anything that would break the row apart.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}